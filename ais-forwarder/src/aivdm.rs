@@ -0,0 +1,429 @@
+// Encoding of AIS messages into AIVDM/AIVDO sentences, for data that does not come from an
+// AIS receiver. See https://gpsd.gitlab.io/gpsd/AIVDM.html for the message layouts.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::nmea;
+
+/// Maximum number of payload characters in a single sentence.
+const MAX_PAYLOAD: usize = 60;
+
+static SEQUENCE_ID: AtomicU8 = AtomicU8::new(0);
+
+#[derive(Debug, Clone, Default)]
+pub struct Position {
+    pub mmsi: u32,
+    pub nav_status: Option<u8>,
+    pub rot: Option<f64>, // Degrees per minute, positive is turning to starboard
+    pub sog: Option<f64>, // Knots
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub cog: Option<f64>,
+    pub heading: Option<f64>,
+    pub timestamp_seconds: Option<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Static {
+    pub mmsi: u32,
+    pub imo: Option<u32>,
+    pub call_sign: Option<String>,
+    pub name: Option<String>,
+    pub ship_type: Option<u8>,
+    pub to_bow: Option<u16>,
+    pub to_stern: Option<u16>,
+    pub to_port: Option<u16>,
+    pub to_starboard: Option<u16>,
+    pub destination: Option<String>,
+}
+
+struct BitWriter {
+    bits: Vec<bool>,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter { bits: Vec::new() }
+    }
+
+    fn push(&mut self, value: u64, width: usize) {
+        for i in (0..width).rev() {
+            self.bits.push((value >> i) & 1 == 1);
+        }
+    }
+
+    fn push_signed(&mut self, value: i64, width: usize) {
+        self.push((value as u64) & ((1 << width) - 1), width);
+    }
+
+    fn push_text(&mut self, text: Option<&str>, chars: usize) {
+        let text = text.unwrap_or("").to_uppercase();
+        let mut text = text.bytes();
+        for _ in 0..chars {
+            let c = match text.next() {
+                Some(c @ 32..=95) => c,
+                Some(_) => b' ',
+                None => b'@',
+            };
+            self.push((if c >= 64 { c - 64 } else { c }) as u64, 6);
+        }
+    }
+
+    // Returns the armored payload and the number of fill bits.
+    fn payload(&self) -> (String, usize) {
+        let fill_bits = (6 - self.bits.len() % 6) % 6;
+        let payload = self
+            .bits
+            .chunks(6)
+            .map(|chunk| {
+                let mut value = 0u8;
+                for i in 0..6 {
+                    value <<= 1;
+                    if chunk.get(i) == Some(&true) {
+                        value |= 1;
+                    }
+                }
+                (if value < 40 { value + 48 } else { value + 56 }) as char
+            })
+            .collect();
+        (payload, fill_bits)
+    }
+}
+
+fn scaled(value: Option<f64>, scale: f64, max: f64, not_available: u64) -> u64 {
+    match value {
+        Some(value) if value >= 0.0 => (value * scale).round().min(max) as u64,
+        _ => not_available,
+    }
+}
+
+fn lat_long(value: Option<f64>, not_available: f64) -> i64 {
+    (value.unwrap_or(not_available) * 600000.0).round() as i64
+}
+
+fn rot(rot: Option<f64>) -> i64 {
+    match rot {
+        Some(rot) => {
+            let ais = 4.733 * rot.abs().sqrt();
+            (ais.round().min(126.0) as i64) * if rot < 0.0 { -1 } else { 1 }
+        }
+        None => -128,
+    }
+}
+
+/// Message type 1, position report class A.
+pub fn encode_position(position: &Position, own_vessel: bool) -> String {
+    let mut bits = BitWriter::new();
+    bits.push(1, 6);
+    bits.push(0, 2); // Repeat indicator
+    bits.push(position.mmsi as u64, 30);
+    bits.push(position.nav_status.unwrap_or(15) as u64, 4);
+    bits.push_signed(rot(position.rot), 8);
+    bits.push(scaled(position.sog, 10.0, 1022.0, 1023), 10);
+    bits.push(0, 1); // Position accuracy
+    bits.push_signed(lat_long(position.longitude, 181.0), 28);
+    bits.push_signed(lat_long(position.latitude, 91.0), 27);
+    bits.push(scaled(position.cog, 10.0, 3599.0, 3600), 12);
+    bits.push(scaled(position.heading, 1.0, 359.0, 511), 9);
+    bits.push(position.timestamp_seconds.unwrap_or(60) as u64, 6);
+    bits.push(0, 2); // Maneuver indicator
+    bits.push(0, 3); // Spare
+    bits.push(0, 1); // RAIM
+    bits.push(0, 19); // Radio status
+    sentences(&bits, own_vessel)
+}
+
+/// Message type 5, static and voyage related data.
+pub fn encode_static(data: &Static, own_vessel: bool) -> String {
+    let mut bits = BitWriter::new();
+    bits.push(5, 6);
+    bits.push(0, 2); // Repeat indicator
+    bits.push(data.mmsi as u64, 30);
+    bits.push(0, 2); // AIS version
+    bits.push(data.imo.unwrap_or(0) as u64, 30);
+    bits.push_text(data.call_sign.as_deref(), 7);
+    bits.push_text(data.name.as_deref(), 20);
+    bits.push(data.ship_type.unwrap_or(0) as u64, 8);
+    bits.push(data.to_bow.unwrap_or(0).min(511) as u64, 9);
+    bits.push(data.to_stern.unwrap_or(0).min(511) as u64, 9);
+    bits.push(data.to_port.unwrap_or(0).min(63) as u64, 6);
+    bits.push(data.to_starboard.unwrap_or(0).min(63) as u64, 6);
+    bits.push(1, 4); // EPFD: GPS
+    bits.push(0, 4); // ETA month
+    bits.push(0, 5); // ETA day
+    bits.push(24, 5); // ETA hour
+    bits.push(60, 6); // ETA minute
+    bits.push(0, 8); // Draught
+    bits.push_text(data.destination.as_deref(), 20);
+    bits.push(0, 1); // DTE
+    bits.push(0, 1); // Spare
+    sentences(&bits, own_vessel)
+}
+
+/// Message type 18, standard class B position report.
+pub fn encode_class_b_position(position: &Position, own_vessel: bool) -> String {
+    let mut bits = BitWriter::new();
+    bits.push(18, 6);
+    bits.push(0, 2); // Repeat indicator
+    bits.push(position.mmsi as u64, 30);
+    bits.push(0, 8); // Reserved
+    bits.push(scaled(position.sog, 10.0, 1022.0, 1023), 10);
+    bits.push(0, 1); // Position accuracy
+    bits.push_signed(lat_long(position.longitude, 181.0), 28);
+    bits.push_signed(lat_long(position.latitude, 91.0), 27);
+    bits.push(scaled(position.cog, 10.0, 3599.0, 3600), 12);
+    bits.push(scaled(position.heading, 1.0, 359.0, 511), 9);
+    bits.push(position.timestamp_seconds.unwrap_or(60) as u64, 6);
+    bits.push(0, 2); // Reserved
+    bits.push(1, 1); // CS unit
+    bits.push(0, 1); // Display
+    bits.push(0, 1); // DSC
+    bits.push(1, 1); // Band
+    bits.push(0, 1); // Message 22
+    bits.push(0, 1); // Assigned
+    bits.push(0, 1); // RAIM
+    bits.push(0, 20); // Radio status
+    sentences(&bits, own_vessel)
+}
+
+/// Message type 24, class B static data. Both part A and part B are returned.
+pub fn encode_class_b_static(data: &Static, own_vessel: bool) -> String {
+    let mut part_a = BitWriter::new();
+    part_a.push(24, 6);
+    part_a.push(0, 2); // Repeat indicator
+    part_a.push(data.mmsi as u64, 30);
+    part_a.push(0, 2); // Part number
+    part_a.push_text(data.name.as_deref(), 20);
+
+    let mut part_b = BitWriter::new();
+    part_b.push(24, 6);
+    part_b.push(0, 2); // Repeat indicator
+    part_b.push(data.mmsi as u64, 30);
+    part_b.push(1, 2); // Part number
+    part_b.push(data.ship_type.unwrap_or(0) as u64, 8);
+    part_b.push_text(None, 3); // Vendor ID
+    part_b.push(0, 4); // Unit model code
+    part_b.push(0, 20); // Serial number
+    part_b.push_text(data.call_sign.as_deref(), 7);
+    part_b.push(data.to_bow.unwrap_or(0).min(511) as u64, 9);
+    part_b.push(data.to_stern.unwrap_or(0).min(511) as u64, 9);
+    part_b.push(data.to_port.unwrap_or(0).min(63) as u64, 6);
+    part_b.push(data.to_starboard.unwrap_or(0).min(63) as u64, 6);
+    part_b.push(0, 6); // Spare
+
+    let mut result = sentences(&part_a, own_vessel);
+    result.push_str(&sentences(&part_b, own_vessel));
+    result
+}
+
+// Split the payload over as many sentences as needed, each terminated by CR LF.
+fn sentences(bits: &BitWriter, own_vessel: bool) -> String {
+    let (payload, fill_bits) = bits.payload();
+    let talker = if own_vessel { "AIVDO" } else { "AIVDM" };
+    let chunks = payload
+        .as_bytes()
+        .chunks(MAX_PAYLOAD)
+        .map(|chunk| String::from_utf8_lossy(chunk).to_string())
+        .collect::<Vec<_>>();
+    let sequence_id = if chunks.len() > 1 {
+        (SEQUENCE_ID.fetch_add(1, Ordering::Relaxed) % 10).to_string()
+    } else {
+        String::new()
+    };
+
+    let mut result = String::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let fill = if i + 1 == chunks.len() { fill_bits } else { 0 };
+        let sentence = format!(
+            "{},{},{},{},A,{},{}",
+            talker,
+            chunks.len(),
+            i + 1,
+            sequence_id,
+            chunk,
+            fill
+        );
+        result.push_str(&format!(
+            "!{}*{:02X}\r\n",
+            sentence,
+            nmea::checksum(&sentence)
+        ));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nmea_parser::ais::AisClass;
+    use nmea_parser::{NmeaParser, ParsedMessage};
+
+    fn position() -> Position {
+        Position {
+            mmsi: 244123456,
+            nav_status: Some(8),
+            rot: Some(-12.0),
+            sog: Some(5.2),
+            latitude: Some(53.018724),
+            longitude: Some(-5.402057),
+            cog: Some(181.3),
+            heading: Some(183.0),
+            timestamp_seconds: Some(19),
+        }
+    }
+
+    fn data() -> Static {
+        Static {
+            mmsi: 244123456,
+            imo: Some(9074729),
+            call_sign: Some("PD1234".to_string()),
+            name: Some("Sea Breeze".to_string()),
+            ship_type: Some(36),
+            to_bow: Some(9),
+            to_stern: Some(3),
+            to_port: Some(2),
+            to_starboard: Some(2),
+            destination: Some("Den Helder".to_string()),
+        }
+    }
+
+    // Parse all sentences and return the message that the last complete one gave.
+    fn decode(sentences: &str) -> ParsedMessage {
+        let mut parser = NmeaParser::new();
+        let mut result = ParsedMessage::Incomplete;
+        for sentence in sentences.lines() {
+            match parser.parse_sentence(sentence).unwrap() {
+                ParsedMessage::Incomplete => {}
+                message => result = message,
+            }
+        }
+        result
+    }
+
+    // The payload length and number of fill bits of each sentence.
+    fn payloads(sentences: &str) -> Vec<(usize, u8)> {
+        sentences
+            .lines()
+            .map(|sentence| {
+                let fields = sentence
+                    .split('*')
+                    .next()
+                    .unwrap()
+                    .split(',')
+                    .collect::<Vec<_>>();
+                (fields[5].len(), fields[6].parse().unwrap())
+            })
+            .collect()
+    }
+
+    fn assert_near(value: Option<f64>, expected: f64, tolerance: f64) {
+        let value = value.unwrap();
+        assert!(
+            (value - expected).abs() <= tolerance,
+            "{} is not {}",
+            value,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_position() {
+        let sentences = encode_position(&position(), false);
+        assert!(sentences.starts_with("!AIVDM,1,1,,A,1"));
+        assert_eq!(payloads(&sentences), [(28, 0)]); // 168 bits
+        let ParsedMessage::VesselDynamicData(data) = decode(&sentences) else {
+            panic!("no position in {}", sentences);
+        };
+        assert!(!data.own_vessel);
+        assert_eq!(data.ais_type, AisClass::ClassA);
+        assert_eq!(data.mmsi, 244123456);
+        assert_eq!(data.nav_status as u8, 8);
+        // ROT is sent as 4.733 √ROT rounded to an integer, here 16
+        assert_near(data.rot, -(16.0_f64 / 4.733).powi(2), 0.01);
+        assert_near(data.sog_knots, 5.2, 0.01);
+        assert_near(data.latitude, 53.018724, 0.00001);
+        assert_near(data.longitude, -5.402057, 0.00001);
+        assert_near(data.cog, 181.3, 0.01);
+        assert_near(data.heading_true, 183.0, 0.01);
+        assert_eq!(data.timestamp_seconds, 19);
+    }
+
+    #[test]
+    fn test_position_not_available() {
+        let position = Position {
+            mmsi: 244123456,
+            ..Default::default()
+        };
+        let ParsedMessage::VesselDynamicData(data) = decode(&encode_position(&position, true))
+        else {
+            panic!("no position");
+        };
+        assert!(data.own_vessel);
+        assert_eq!(data.rot, None);
+        assert_eq!(data.sog_knots, None);
+        assert_eq!(data.latitude, None);
+        assert_eq!(data.longitude, None);
+        assert_eq!(data.cog, None);
+        assert_eq!(data.heading_true, None);
+        assert_eq!(data.timestamp_seconds, 60);
+    }
+
+    #[test]
+    fn test_static() {
+        let sentences = encode_static(&data(), false);
+        // 424 bits are 71 characters with 2 fill bits, over two sentences
+        assert_eq!(payloads(&sentences), [(60, 0), (11, 2)]);
+        let ParsedMessage::VesselStaticData(data) = decode(&sentences) else {
+            panic!("no static data in {}", sentences);
+        };
+        assert_eq!(data.ais_type, AisClass::ClassA);
+        assert_eq!(data.mmsi, 244123456);
+        assert_eq!(data.imo_number, Some(9074729));
+        assert_eq!(data.call_sign.as_deref(), Some("PD1234"));
+        assert_eq!(data.name.as_deref(), Some("SEA BREEZE"));
+        assert_eq!(data.ship_type as u8, 36);
+        assert_eq!(data.dimension_to_bow, Some(9));
+        assert_eq!(data.dimension_to_stern, Some(3));
+        assert_eq!(data.dimension_to_port, Some(2));
+        assert_eq!(data.dimension_to_starboard, Some(2));
+        assert_eq!(data.destination.as_deref(), Some("DEN HELDER"));
+    }
+
+    #[test]
+    fn test_class_b_position() {
+        let sentences = encode_class_b_position(&position(), true);
+        assert!(sentences.starts_with("!AIVDO,1,1,,A,B"));
+        assert_eq!(payloads(&sentences), [(28, 0)]); // 168 bits
+        let ParsedMessage::VesselDynamicData(data) = decode(&sentences) else {
+            panic!("no position in {}", sentences);
+        };
+        assert!(data.own_vessel);
+        assert_eq!(data.ais_type, AisClass::ClassB);
+        assert_eq!(data.mmsi, 244123456);
+        assert_near(data.sog_knots, 5.2, 0.01);
+        assert_near(data.latitude, 53.018724, 0.00001);
+        assert_near(data.longitude, -5.402057, 0.00001);
+        assert_near(data.cog, 181.3, 0.01);
+        assert_near(data.heading_true, 183.0, 0.01);
+        assert_eq!(data.timestamp_seconds, 19);
+    }
+
+    #[test]
+    fn test_class_b_static() {
+        let sentences = encode_class_b_static(&data(), false);
+        // Part A has 160 bits, 27 characters with 2 fill bits, part B 168 bits
+        assert_eq!(payloads(&sentences), [(27, 2), (28, 0)]);
+        let ParsedMessage::VesselStaticData(data) = decode(&sentences) else {
+            panic!("no static data in {}", sentences);
+        };
+        assert_eq!(data.ais_type, AisClass::ClassB);
+        assert_eq!(data.mmsi, 244123456);
+        assert_eq!(data.name.as_deref(), Some("SEA BREEZE"));
+        assert_eq!(data.call_sign.as_deref(), Some("PD1234"));
+        assert_eq!(data.ship_type as u8, 36);
+        assert_eq!(data.dimension_to_bow, Some(9));
+        assert_eq!(data.dimension_to_stern, Some(3));
+        assert_eq!(data.dimension_to_port, Some(2));
+        assert_eq!(data.dimension_to_starboard, Some(2));
+    }
+}
@@ -0,0 +1,413 @@
+use std::io::{self, BufRead, Read};
+use std::time::{Duration, Instant};
+
+use common::NetworkEndpoint;
+use common::buffer::BufReaderDirectWriter;
+use common::send_message_tcp;
+
+use crate::report::LocationReport;
+
+const APRS_TOCALL: &str = "APRS";
+const APRS_DEFAULT_INTERVAL: &str = "300";
+const APRS_LOGIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Send a location report to an APRS-IS server as a timestamped position packet.
+//
+// aprs://rotate.aprs2.net:14580?callsign=PD1ABC-9&passcode=12345
+//
+// Options:
+// - callsign, passcode: APRS-IS login, required.
+// - format: `uncompressed` (default) or `compressed`.
+// - symbol: symbol table and code, default `/s` (ship), use `/Y` for a sailing yacht.
+// - interval: minimum number of seconds between packets, default 300. Reports that arrive
+//   sooner are dropped, as APRS-IS has no use for a dense track.
+// - comment: free text appended to each packet.
+pub fn send_message_aprs(
+    nmea_message: &[u8],
+    key: &str,
+    address: &mut NetworkEndpoint,
+) -> io::Result<()> {
+    let report = LocationReport::from_message(nmea_message, key)?;
+    let interval = address.parse_option::<u64>(key, "interval", APRS_DEFAULT_INTERVAL)?;
+
+    let elapsed = address.last_sent.map(|last_sent| last_sent.elapsed());
+    if elapsed.is_some_and(|elapsed| elapsed < Duration::from_secs(interval)) {
+        log::debug!(
+            "{}: Skipping position as last packet was sent {} seconds ago",
+            key,
+            elapsed.unwrap_or_default().as_secs()
+        );
+        return Ok(());
+    }
+
+    let packet = format_packet(&report, address)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", key, e)))?;
+
+    address.tcp_stream.retain(|writer| {
+        if writer.peer_addr().is_err() {
+            log::warn!("Removing disconnected TCP stream");
+            false
+        } else {
+            true
+        }
+    });
+    if address.tcp_stream.is_empty() {
+        let stream = login(key, address)?;
+        address.tcp_stream.push(stream);
+    }
+    if let Some(tcp_stream) = address.tcp_stream.get_mut(0) {
+        // The server sends keepalive comments that we are not interested in, but they must
+        // be read or the server will eventually drop the connection.
+        drain(tcp_stream);
+        send_message_tcp(tcp_stream, packet.as_bytes()).map_err(|e| {
+            address.tcp_stream.clear();
+            io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("send_message aprs {} ({}): {}", key, address.addr, e),
+            )
+        })?;
+        address.last_sent = Some(Instant::now());
+        log::debug!("{}: Sent {} to {}", key, packet.trim_end(), address);
+    }
+    Ok(())
+}
+
+fn login(
+    key: &str,
+    address: &NetworkEndpoint,
+) -> io::Result<BufReaderDirectWriter<std::net::TcpStream>> {
+    let callsign = required_option(key, address, "callsign")?;
+    let passcode = required_option(key, address, "passcode")?;
+
+    let stream = std::net::TcpStream::connect(address.addr).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("{} ({}): {}", key, address.addr, e),
+        )
+    })?;
+    stream.set_read_timeout(Some(APRS_LOGIN_TIMEOUT))?;
+    let mut stream = BufReaderDirectWriter::new(stream);
+
+    let login = format!(
+        "user {} pass {} vers ais-forwarder {}\r\n",
+        callsign,
+        passcode,
+        env!("CARGO_PKG_VERSION")
+    );
+    send_message_tcp(&mut stream, login.as_bytes())?;
+
+    // Wait for the `# logresp` line, skipping the server banner.
+    let mut response = String::new();
+    loop {
+        response.clear();
+        if stream.read_line(&mut response)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                format!("{} ({}): connection closed during login", key, address.addr),
+            ));
+        }
+        let response = response.trim_end();
+        log::debug!("{}: {}", key, response);
+        if let Some(logresp) = response.strip_prefix("# logresp ") {
+            if logresp.contains("unverified") {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("{} ({}): login rejected: {}", key, address.addr, logresp),
+                ));
+            }
+            break;
+        }
+    }
+    log::info!("{}: Logged in to {} as {}", key, address, callsign);
+    Ok(stream)
+}
+
+fn drain(stream: &mut BufReaderDirectWriter<std::net::TcpStream>) {
+    if stream.set_nonblocking(true).is_err() {
+        return;
+    }
+    let mut buffer = [0u8; 512];
+    while let Ok(n) = stream.read(&mut buffer) {
+        if n == 0 {
+            break;
+        }
+    }
+    let _ = stream.set_nonblocking(false);
+}
+
+fn format_packet(report: &LocationReport, address: &NetworkEndpoint) -> Result<String, String> {
+    let callsign = address
+        .options
+        .get("callsign")
+        .ok_or("missing callsign option")?;
+    let symbol = address.option("symbol", "/s").as_bytes();
+    if symbol.len() != 2 {
+        return Err(format!(
+            "invalid symbol '{}', should be table and code",
+            address.option("symbol", "/s")
+        ));
+    }
+    let (table, code) = (symbol[0] as char, symbol[1] as char);
+    let timestamp = report.timestamp.unwrap_or_else(chrono::Utc::now);
+
+    let position = match address.option("format", "uncompressed") {
+        "uncompressed" => format_uncompressed(report, table, code),
+        "compressed" => format_compressed(report, table, code),
+        format => return Err(format!("unknown format '{}'", format)),
+    };
+
+    Ok(format!(
+        "{}>{},TCPIP*:/{}{}{}\r\n",
+        callsign,
+        APRS_TOCALL,
+        timestamp.format("%d%H%Mz"),
+        position,
+        address.option("comment", "")
+    ))
+}
+
+// `DDMM.hhN/DDDMM.hhWsCCC/SSS`
+fn format_uncompressed(report: &LocationReport, table: char, code: char) -> String {
+    let lat = format_degrees_minutes(
+        report.latitude,
+        2,
+        if report.latitude >= 0.0 { 'N' } else { 'S' },
+    );
+    let lon = format_degrees_minutes(
+        report.longitude,
+        3,
+        if report.longitude >= 0.0 { 'E' } else { 'W' },
+    );
+    let mut position = format!("{}{}{}{}", lat, table, lon, code);
+    if let (Some(cog), Some(sog)) = (report.cog, report.sog) {
+        let course = match cog.round() as u32 % 360 {
+            0 => 360, // 000 means unknown
+            course => course,
+        };
+        position.push_str(&format!(
+            "{:03}/{:03}",
+            course,
+            sog.round().min(999.0) as u32
+        ));
+    }
+    position
+}
+
+fn format_degrees_minutes(value: f64, width: usize, hemisphere: char) -> String {
+    let value = value.abs();
+    let mut degrees = value.trunc() as u32;
+    let mut hundredths = ((value - value.trunc()) * 6000.0).round() as u32;
+    if hundredths >= 6000 {
+        degrees += 1;
+        hundredths -= 6000;
+    }
+    format!(
+        "{:0width$}{:02}.{:02}{}",
+        degrees,
+        hundredths / 100,
+        hundredths % 100,
+        hemisphere,
+        width = width
+    )
+}
+
+// `TYYYYXXXXScsT` in base91, see chapter 9 of the APRS 1.01 specification.
+fn format_compressed(report: &LocationReport, table: char, code: char) -> String {
+    let y = (380926.0 * (90.0 - report.latitude)).round() as u32;
+    let x = (190463.0 * (180.0 + report.longitude)).round() as u32;
+    let course_speed = match (report.cog, report.sog) {
+        (Some(cog), Some(sog)) => {
+            let c = ((cog.round() as u32 % 360) / 4) as u8;
+            let s = ((sog + 1.0).ln() / 1.08_f64.ln()).round().min(89.0) as u8;
+            format!("{}{}", (c + 33) as char, (s + 33) as char)
+        }
+        _ => "  ".to_string(),
+    };
+    // Current GPS fix, NMEA source RMC, origin software
+    let compression_type = (0b0011_1010u8 + 33) as char;
+    format!(
+        "{}{}{}{}{}{}",
+        table,
+        base91(y),
+        base91(x),
+        code,
+        course_speed,
+        compression_type
+    )
+}
+
+fn base91(mut value: u32) -> String {
+    let mut digits = [b'!'; 4];
+    for digit in digits.iter_mut().rev() {
+        *digit = (value % 91) as u8 + 33;
+        value /= 91;
+    }
+    String::from_utf8_lossy(&digits).to_string()
+}
+
+fn required_option<'a>(
+    key: &str,
+    address: &'a NetworkEndpoint,
+    option: &str,
+) -> io::Result<&'a str> {
+    address
+        .options
+        .get(option)
+        .map(|v| v.as_str())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} ({}): missing {} option", key, address, option),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    // The example of chapter 9 of the APRS 1.01 specification.
+    fn report() -> LocationReport {
+        LocationReport {
+            id: "244123456".to_string(),
+            timestamp: Some(Utc.with_ymd_and_hms(2025, 5, 15, 12, 35, 19).unwrap()),
+            latitude: 49.5,
+            longitude: -72.75,
+            sog: Some(36.2),
+            cog: Some(88.0),
+            heading: None,
+            nav_status: None,
+            rot: None,
+            nmea: String::new(),
+        }
+    }
+
+    fn endpoint(address: &str, options: &str) -> NetworkEndpoint {
+        format!(
+            "aprs://{}?callsign=PD1ABC-9&passcode=12345{}",
+            address, options
+        )
+        .parse()
+        .unwrap()
+    }
+
+    // A server that sends a banner, reads the login line and answers with `logresp`, then
+    // reads one more line. Returns its address and the lines it received.
+    fn server(logresp: &'static str) -> (String, std::thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream = BufReaderDirectWriter::new(stream);
+            stream.write_all(b"# aprsc 2.1.14\r\n").unwrap();
+            let mut lines = Vec::new();
+            let mut line = String::new();
+            stream.read_line(&mut line).unwrap();
+            lines.push(line.trim_end().to_string());
+            stream
+                .write_all(format!("# logresp PD1ABC-9 {}\r\n", logresp).as_bytes())
+                .unwrap();
+            line.clear();
+            if stream.read_line(&mut line).unwrap() > 0 {
+                lines.push(line.trim_end().to_string());
+            }
+            lines
+        });
+        (address, handle)
+    }
+
+    #[test]
+    fn test_base91() {
+        assert_eq!(base91(0), "!!!!");
+        assert_eq!(base91(15427503), "5L!!");
+        assert_eq!(base91(20427156), "<*e7");
+    }
+
+    #[test]
+    fn test_format_compressed() {
+        // The specification truncates 20427156.75 to <*e7, we round to <*e8
+        assert_eq!(format_compressed(&report(), '/', 's'), "/5L!!<*e8s7P[");
+        let report = LocationReport {
+            sog: None,
+            ..report()
+        };
+        assert_eq!(format_compressed(&report, '/', 's'), "/5L!!<*e8s  [");
+    }
+
+    #[test]
+    fn test_format_uncompressed() {
+        assert_eq!(
+            format_uncompressed(&report(), '/', 'Y'),
+            "4930.00N/07245.00WY088/036"
+        );
+        let report = LocationReport {
+            latitude: -52.999999,
+            longitude: 4.5,
+            cog: Some(359.7),
+            sog: Some(5.4),
+            ..report()
+        };
+        // Course 0 is sent as 360, minutes that round to 60 carry into the degrees
+        assert_eq!(
+            format_uncompressed(&report, '/', 's'),
+            "5300.00S/00430.00Es360/005"
+        );
+    }
+
+    #[test]
+    fn test_format_packet() {
+        let address = endpoint("127.0.0.1:14580", "&comment=Test");
+        assert_eq!(
+            format_packet(&report(), &address).unwrap(),
+            "PD1ABC-9>APRS,TCPIP*:/151235z4930.00N/07245.00Ws088/036Test\r\n"
+        );
+        let address = endpoint("127.0.0.1:14580", "&format=compressed&symbol=/Y");
+        assert_eq!(
+            format_packet(&report(), &address).unwrap(),
+            "PD1ABC-9>APRS,TCPIP*:/151235z/5L!!<*e8Y7P[\r\n"
+        );
+        let address = endpoint("127.0.0.1:14580", "&symbol=Y");
+        assert!(format_packet(&report(), &address).is_err());
+        let address = endpoint("127.0.0.1:14580", "&format=mic-e");
+        assert!(format_packet(&report(), &address).is_err());
+    }
+
+    #[test]
+    fn test_login_and_send() {
+        let (address, server) = server("verified, server T2TEST");
+        let mut address = endpoint(&address, "&interval=60");
+        let message =
+            br#"{"id":"244123456","time":"2025-05-15T12:35:19Z","lat":49.5,"lon":-72.75}"#;
+        send_message_aprs(message, "test", &mut address).unwrap();
+        // Within the interval, so not sent
+        send_message_aprs(message, "test", &mut address).unwrap();
+        address.tcp_stream.clear();
+
+        let lines = server.join().unwrap();
+        assert_eq!(
+            lines[0],
+            format!(
+                "user PD1ABC-9 pass 12345 vers ais-forwarder {}",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+        assert_eq!(
+            lines[1..],
+            ["PD1ABC-9>APRS,TCPIP*:/151235z4930.00N/07245.00Ws"]
+        );
+    }
+
+    #[test]
+    fn test_login_unverified() {
+        let (address, server) = server("unverified, server T2TEST");
+        let address = endpoint(&address, "");
+        let Err(e) = login("test", &address) else {
+            panic!("login should be rejected");
+        };
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        server.join().unwrap();
+    }
+}
@@ -1,16 +1,76 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::collections::HashMap;
 use std::path::PathBuf;
 
 use sled::*;
 
+const QUEUE_PREFIX: &str = "location-";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Eviction {
+    /// Drop the oldest messages.
+    Oldest,
+    /// Keep one message per `thin_minutes` for the oldest messages, then drop the oldest.
+    Thin,
+}
+
+/// Limits that apply to the queue of each destination, 0 means no limit.
+#[derive(Debug, Clone)]
+pub struct CacheLimits {
+    pub max_entries: usize,
+    pub max_bytes: usize,
+    pub max_age: u64, // Seconds
+    pub eviction: Eviction,
+    pub thin_minutes: i64,
+}
+
+impl CacheLimits {
+    pub fn from_config(general: &HashMap<String, String>) -> std::result::Result<Self, String> {
+        fn number<T: std::str::FromStr>(
+            general: &HashMap<String, String>,
+            key: &str,
+            default: T,
+        ) -> std::result::Result<T, String>
+        where
+            T::Err: std::fmt::Display,
+        {
+            match general.get(key) {
+                None => Ok(default),
+                Some(v) => v
+                    .parse::<T>()
+                    .map_err(|e| format!("Invalid {}: {}", key, e)),
+            }
+        }
+
+        let eviction = match general.get("cache_eviction").map(|v| v.as_str()) {
+            None | Some("thin") => Eviction::Thin,
+            Some("oldest") => Eviction::Oldest,
+            Some(v) => return Err(format!("Invalid cache_eviction '{}'", v)),
+        };
+        Ok(CacheLimits {
+            max_entries: number(general, "cache_max_entries", 50_000)?,
+            max_bytes: number(general, "cache_max_bytes", 5_000_000)?,
+            max_age: number(general, "cache_max_age", 0)?,
+            eviction,
+            thin_minutes: number(general, "cache_thin_minutes", 10)?,
+        })
+    }
+}
+
+/// Messages that still have to be delivered, with a separate queue (sled tree) per
+/// destination so that each destination is drained on its own.
 #[derive(Debug, Clone)]
 pub struct Persistence {
     db: Db,
-    count: usize,
+    queues: HashMap<String, Tree>,
+    counts: HashMap<String, usize>,
+    bytes: HashMap<String, usize>,
+    limits: CacheLimits,
 }
 
 #[allow(dead_code)]
 impl Persistence {
-    pub fn new(cache_dir: &str) -> Self {
+    pub fn new(cache_dir: &str, limits: CacheLimits) -> Self {
         let database_path = PathBuf::from(cache_dir);
         if !database_path.exists() {
             std::fs::create_dir_all(&database_path).expect("Cannot create database directory");
@@ -21,27 +81,85 @@ impl Persistence {
             .path(&database_path)
             .open()
             .expect(format!("Cannot open database {}", database_path.display()).as_str());
-        let count = db.len();
 
-        let this = Persistence { db, count };
+        let mut this = Persistence {
+            db,
+            queues: HashMap::new(),
+            counts: HashMap::new(),
+            bytes: HashMap::new(),
+            limits,
+        };
+        this.migrate();
 
         log::debug!("database loaded from {}", database_path.display());
 
         this
     }
 
-    pub fn store(&mut self, key: &[u8], value: &[u8]) {
-        if self.db.insert(key, value).unwrap().is_none() {
-            self.count += 1;
+    // Older versions stored all messages in the default tree with key "{timestamp}-{destination}",
+    // move them to the queue of their destination.
+    fn migrate(&mut self) {
+        let mut migrated = 0;
+        for item in self.db.iter() {
+            let (key, value) = match item {
+                Ok(item) => item,
+                Err(e) => {
+                    log::error!("Error reading from database: {}", e);
+                    continue;
+                }
+            };
+            let skey = String::from_utf8_lossy(&key).to_string();
+            if let Some((timestamp, destination)) = skey.split_once(" UTC-") {
+                self.store(destination, format!("{} UTC", timestamp).as_bytes(), &value);
+                self.db.remove(&key).unwrap();
+                migrated += 1;
+            }
+        }
+        if migrated > 0 {
+            log::info!(
+                "Moved {} cached messages to per destination queues",
+                migrated
+            );
+            self.flush();
         }
     }
 
-    pub fn iter(&self) -> sled::Iter {
-        self.db.iter()
+    pub fn open_tree(&self, name: &str) -> Tree {
+        self.db
+            .open_tree(name)
+            .unwrap_or_else(|e| panic!("Cannot open database tree {}: {}", name, e))
     }
 
-    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        match self.db.get(key) {
+    fn queue(&mut self, destination: &str) -> &Tree {
+        if !self.queues.contains_key(destination) {
+            let tree = self.open_tree(&format!("{}{}", QUEUE_PREFIX, destination));
+            let bytes = tree
+                .iter()
+                .filter_map(|item| item.ok())
+                .map(|(_, value)| value.len())
+                .sum();
+            self.counts.insert(destination.to_string(), tree.len());
+            self.bytes.insert(destination.to_string(), bytes);
+            self.queues.insert(destination.to_string(), tree);
+        }
+        &self.queues[destination]
+    }
+
+    pub fn store(&mut self, destination: &str, key: &[u8], value: &[u8]) {
+        match self.queue(destination).insert(key, value).unwrap() {
+            None => *self.counts.entry(destination.to_string()).or_default() += 1,
+            Some(old) => *self.bytes.entry(destination.to_string()).or_default() -= old.len(),
+        }
+        *self.bytes.entry(destination.to_string()).or_default() += value.len();
+        self.enforce_limits(destination);
+    }
+
+    pub fn iter(&mut self, destination: &str) -> sled::Iter {
+        self.queue(destination).iter()
+    }
+
+    pub fn get(&mut self, destination: &str, key: &[u8]) -> Option<Vec<u8>> {
+        match self.queue(destination).get(key) {
             Ok(Some(value)) => Some(value.to_vec()),
             Ok(None) => None,
             Err(e) => {
@@ -51,21 +169,267 @@ impl Persistence {
         }
     }
 
-    pub fn remove(&mut self, key: &[u8]) {
-        if self.db.remove(key).unwrap().is_some() {
-            self.count -= 1;
+    pub fn remove(&mut self, destination: &str, key: &[u8]) {
+        if let Some(old) = self.queue(destination).remove(key).unwrap() {
+            *self.counts.entry(destination.to_string()).or_default() -= 1;
+            *self.bytes.entry(destination.to_string()).or_default() -= old.len();
         }
     }
 
+    /// A number that is higher than all numbers returned before, also across restarts.
+    pub fn generate_id(&self) -> u64 {
+        self.db.generate_id().unwrap_or_else(|e| {
+            log::error!("Cannot generate id: {}", e);
+            0
+        })
+    }
+
     pub fn flush(&self) {
         self.db.flush().unwrap();
     }
 
-    pub fn clear(&self) {
-        self.db.clear().unwrap();
+    pub fn clear(&mut self, destination: &str) {
+        self.queue(destination).clear().unwrap();
+        self.counts.insert(destination.to_string(), 0);
+        self.bytes.insert(destination.to_string(), 0);
+    }
+
+    pub fn count(&mut self, destination: &str) -> usize {
+        self.queue(destination);
+        self.counts[destination]
+    }
+
+    fn over_limits(&self, destination: &str) -> bool {
+        let limits = &self.limits;
+        let count = self.counts.get(destination).copied().unwrap_or(0);
+        let bytes = self.bytes.get(destination).copied().unwrap_or(0);
+        (limits.max_entries > 0 && count > limits.max_entries)
+            || (limits.max_bytes > 0 && bytes > limits.max_bytes)
+    }
+
+    // Evict messages that are too old, then thin out or drop the oldest messages until the
+    // queue is within its limits.
+    fn enforce_limits(&mut self, destination: &str) {
+        let mut expired = 0;
+        if self.limits.max_age > 0 {
+            let oldest = Utc::now() - chrono::Duration::seconds(self.limits.max_age as i64);
+            for (key, _) in self.iter(destination).filter_map(|item| item.ok()) {
+                if key_time(&key).is_some_and(|ts| ts >= oldest) {
+                    break;
+                }
+                self.remove(destination, &key);
+                expired += 1;
+            }
+        }
+
+        let mut thinned = 0;
+        if self.limits.eviction == Eviction::Thin && self.over_limits(destination) {
+            let spacing = chrono::Duration::minutes(self.limits.thin_minutes);
+            let mut last_kept: Option<DateTime<Utc>> = None;
+            for (key, _) in self.iter(destination).filter_map(|item| item.ok()) {
+                if !self.over_limits(destination) {
+                    break;
+                }
+                let ts = key_time(&key);
+                match (ts, last_kept) {
+                    (Some(ts), Some(last_kept)) if ts < last_kept + spacing => {
+                        self.remove(destination, &key);
+                        thinned += 1;
+                    }
+                    _ => last_kept = ts,
+                }
+            }
+        }
+
+        let mut dropped = 0;
+        while self.over_limits(destination) {
+            match self.queue(destination).first() {
+                Ok(Some((key, _))) => {
+                    self.remove(destination, &key);
+                    dropped += 1;
+                }
+                _ => break,
+            }
+        }
+
+        if expired + thinned + dropped > 0 {
+            log::warn!(
+                "{}: Cache limits reached, evicted {} expired, {} thinned out and {} oldest messages",
+                destination,
+                expired,
+                thinned,
+                dropped
+            );
+        }
+    }
+}
+
+// Keys are the time of the fix followed by a record number, see `Location::parse_message`.
+// Keys of older versions have no record number.
+fn key_time(key: &[u8]) -> Option<DateTime<Utc>> {
+    let key = String::from_utf8_lossy(key);
+    NaiveDateTime::parse_and_remainder(&key, "%Y-%m-%d %H:%M:%S%.f UTC")
+        .ok()
+        .map(|(ts, _)| ts.and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_time() {
+        let expected = "2025-05-15T12:35:19Z".parse::<DateTime<Utc>>().ok();
+        assert_eq!(key_time(b"2025-05-15 12:35:19 UTC"), expected);
+        assert_eq!(
+            key_time(b"2025-05-15 12:35:19 UTC 00000000000000000042"),
+            expected
+        );
+        assert_eq!(key_time(b"garbage"), None);
+    }
+
+    #[test]
+    fn test_same_second_keys() {
+        let dir = tempdir();
+        let mut persistence = Persistence::new(&dir, limits(0, Eviction::Oldest));
+        let timestamp = "2025-05-15T12:35:19Z".parse::<DateTime<Utc>>().unwrap();
+        for value in ["first", "second"] {
+            let key = format!("{} {:020}", timestamp, persistence.generate_id());
+            persistence.store("test", key.as_bytes(), value.as_bytes());
+        }
+        assert_eq!(values(&mut persistence, "test"), ["first", "second"]);
+        drop(persistence);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_migrate() {
+        let dir = tempdir();
+        {
+            let db = sled::open(&dir).unwrap();
+            db.insert("2025-05-15 12:35:19 UTC-one", "a").unwrap();
+            db.insert("2025-05-15 12:35:20 UTC-two", "b").unwrap();
+            db.insert("2025-05-15 12:35:21 UTC-one", "c").unwrap();
+            db.flush().unwrap();
+        }
+        let mut persistence = Persistence::new(&dir, limits(0, Eviction::Oldest));
+        assert_eq!(persistence.count("one"), 2);
+        assert_eq!(persistence.count("two"), 1);
+        assert_eq!(values(&mut persistence, "one"), ["a", "c"]);
+        assert_eq!(
+            persistence.get("two", b"2025-05-15 12:35:20 UTC"),
+            Some(b"b".to_vec())
+        );
+        assert!(persistence.db.is_empty());
+        drop(persistence);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_separate_queues() {
+        let dir = tempdir();
+        let mut persistence = Persistence::new(&dir, limits(0, Eviction::Oldest));
+        persistence.store("one", b"1", b"a");
+        persistence.store("two", b"1", b"b");
+        persistence.remove("one", b"1");
+        assert_eq!(persistence.count("one"), 0);
+        assert_eq!(values(&mut persistence, "two"), ["b"]);
+        drop(persistence);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Store a fix every minute from 12:00 and return the minutes that are left.
+    fn store_minutes(limits: CacheLimits, minutes: u32) -> Vec<String> {
+        let dir = tempdir();
+        let mut persistence = Persistence::new(&dir, limits);
+        for minute in 0..minutes {
+            let key = format!("2025-05-15 12:{:02}:00 UTC", minute);
+            persistence.store("test", key.as_bytes(), format!("{:02}", minute).as_bytes());
+        }
+        let result = values(&mut persistence, "test");
+        drop(persistence);
+        std::fs::remove_dir_all(dir).unwrap();
+        result
+    }
+
+    #[test]
+    fn test_evict_oldest() {
+        let left = store_minutes(limits(3, Eviction::Oldest), 5);
+        assert_eq!(left, ["02", "03", "04"]);
+    }
+
+    #[test]
+    fn test_evict_thin() {
+        // The oldest fix is kept, the ones after it within 10 minutes are thinned out
+        let left = store_minutes(limits(3, Eviction::Thin), 5);
+        assert_eq!(left, ["00", "03", "04"]);
+    }
+
+    #[test]
+    fn test_evict_bytes() {
+        let limits = CacheLimits {
+            max_bytes: 5,
+            ..limits(0, Eviction::Oldest)
+        };
+        let left = store_minutes(limits, 5);
+        assert_eq!(left, ["03", "04"]);
+    }
+
+    #[test]
+    fn test_evict_age() {
+        let dir = tempdir();
+        let limits = CacheLimits {
+            max_age: 3600,
+            ..limits(0, Eviction::Thin)
+        };
+        let mut persistence = Persistence::new(&dir, limits);
+        persistence.store("test", b"2025-05-15 12:00:00 UTC", b"old");
+        let now = format!("{} {:020}", Utc::now(), 1);
+        persistence.store("test", now.as_bytes(), b"new");
+        assert_eq!(values(&mut persistence, "test"), ["new"]);
+        drop(persistence);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_limits_from_config() {
+        let general = HashMap::from([
+            ("cache_max_entries".to_string(), "100".to_string()),
+            ("cache_eviction".to_string(), "oldest".to_string()),
+        ]);
+        let limits = CacheLimits::from_config(&general).unwrap();
+        assert_eq!(limits.max_entries, 100);
+        assert_eq!(limits.max_bytes, 5_000_000);
+        assert_eq!(limits.eviction, Eviction::Oldest);
+
+        let general = HashMap::from([("cache_eviction".to_string(), "newest".to_string())]);
+        assert!(CacheLimits::from_config(&general).is_err());
+    }
+
+    fn tempdir() -> String {
+        static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        std::env::temp_dir()
+            .join(format!("ais-forwarder-cache-{}-{}", std::process::id(), n))
+            .display()
+            .to_string()
+    }
+
+    fn limits(max_entries: usize, eviction: Eviction) -> CacheLimits {
+        CacheLimits {
+            max_entries,
+            max_bytes: 0,
+            max_age: 0,
+            eviction,
+            thin_minutes: 10,
+        }
     }
 
-    pub fn count(&self) -> usize {
-        self.count
+    fn values(persistence: &mut Persistence, destination: &str) -> Vec<String> {
+        persistence
+            .iter(destination)
+            .filter_map(|item| item.ok())
+            .map(|(_, value)| String::from_utf8_lossy(&value).to_string())
+            .collect()
     }
 }
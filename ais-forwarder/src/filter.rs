@@ -0,0 +1,388 @@
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+
+use crate::track::{self, METRES_PER_DEGREE, METRES_PER_SECOND_PER_KNOT};
+
+// Number of rejected fixes that agree with each other after which we believe them, for
+// instance when the vessel was moved on a ferry or trailer with the GPS switched off.
+const CONFIRM_FIXES: usize = 3;
+// Error in metres per second of the velocity that we get from SOG and COG.
+const VELOCITY_ERROR: f64 = 0.5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Smoothing {
+    None,
+    Kalman,
+}
+
+/// Settings of the position filter, from the [general] section.
+#[derive(Debug, Clone)]
+pub struct FilterConfig {
+    pub max_speed: f64, // Knots
+    pub accuracy: f64,  // Metres, the expected error of a fix
+    pub smoothing: Smoothing,
+}
+
+impl FilterConfig {
+    pub fn from_config(general: &HashMap<String, String>) -> Result<Self, String> {
+        fn number(
+            general: &HashMap<String, String>,
+            key: &str,
+            default: f64,
+        ) -> Result<f64, String> {
+            match general.get(key).map(|v| v.parse::<f64>()) {
+                None => Ok(default),
+                Some(Ok(v)) if v > 0.0 => Ok(v),
+                Some(Ok(v)) => Err(format!("Invalid {}: {} is not positive", key, v)),
+                Some(Err(e)) => Err(format!("Invalid {}: {}", key, e)),
+            }
+        }
+
+        let smoothing = match general.get("position_smoothing").map(|v| v.as_str()) {
+            None | Some("none") => Smoothing::None,
+            Some("kalman") => Smoothing::Kalman,
+            Some(v) => return Err(format!("Invalid position_smoothing '{}'", v)),
+        };
+        Ok(FilterConfig {
+            max_speed: number(general, "position_max_speed", 50.0)?,
+            accuracy: number(general, "position_accuracy", 25.0)?,
+            smoothing,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Point {
+    latitude: f64,
+    longitude: f64,
+    timestamp: DateTime<Utc>,
+    sog: Option<f64>,
+    cog: Option<f64>,
+}
+
+/// Rejects positions that the vessel cannot have reached since the last good fix, given
+/// the time between the fixes and the reported speed over ground, and optionally smooths
+/// the track with a Kalman filter.
+pub struct PositionFilter {
+    config: FilterConfig,
+    estimate: Option<Point>,
+    variance: f64,          // Of the estimated position, in m² per axis
+    candidates: Vec<Point>, // Rejected fixes that agree with each other
+    rejected: u64,
+}
+
+impl PositionFilter {
+    pub fn new(config: FilterConfig) -> Self {
+        PositionFilter {
+            config,
+            estimate: None,
+            variance: 0.0,
+            candidates: Vec::new(),
+            rejected: 0,
+        }
+    }
+
+    /// Returns the position to report, or None when the fix is rejected.
+    pub fn filter(
+        &mut self,
+        latitude: Option<f64>,
+        longitude: Option<f64>,
+        timestamp: DateTime<Utc>,
+        sog: Option<f64>,
+        cog: Option<f64>,
+    ) -> Option<(f64, f64)> {
+        let (Some(latitude), Some(longitude)) = (latitude, longitude) else {
+            log::warn!("Invalid position: latitude or longitude is None");
+            return None;
+        };
+        if latitude.abs() > 90.0 || longitude.abs() > 180.0 {
+            log::warn!("Invalid position: latitude or longitude out of range");
+            return None;
+        }
+        if latitude.abs() < 0.01 || longitude.abs() < 0.01 {
+            log::warn!("Invalid position: latitude and longitude are too close to zero");
+            return None;
+        }
+        let point = Point {
+            latitude,
+            longitude,
+            timestamp,
+            sog,
+            cog,
+        };
+
+        let Some(estimate) = self.estimate else {
+            return Some(self.reset(point));
+        };
+        if let Err(diagnostic) = self.check_speed(&estimate, &point) {
+            self.rejected += 1;
+            log::warn!(
+                "Rejected position {:.5} {:.5}: {} ({} rejected so far)",
+                latitude,
+                longitude,
+                diagnostic,
+                self.rejected
+            );
+            match self.candidates.last() {
+                Some(last) if self.check_speed(last, &point).is_ok() => self.candidates.push(point),
+                _ => self.candidates = vec![point],
+            }
+            if self.candidates.len() >= CONFIRM_FIXES {
+                log::info!(
+                    "Accepting new position {:.5} {:.5} after {} consistent fixes",
+                    latitude,
+                    longitude,
+                    self.candidates.len()
+                );
+                return Some(self.reset(point));
+            }
+            return None;
+        }
+        self.candidates.clear();
+
+        let point = match self.config.smoothing {
+            Smoothing::None => point,
+            Smoothing::Kalman => self.smooth(&estimate, point),
+        };
+        self.estimate = Some(point);
+        Some((point.latitude, point.longitude))
+    }
+
+    fn reset(&mut self, point: Point) -> (f64, f64) {
+        self.estimate = Some(point);
+        self.variance = self.config.accuracy.powi(2);
+        self.candidates.clear();
+        (point.latitude, point.longitude)
+    }
+
+    // The vessel may sail at up to one and a half times the highest reported SOG plus 5 knots
+    // for gusts and turns, but never faster than `max_speed`. Fixes may be off by `accuracy`.
+    fn check_speed(&self, from: &Point, to: &Point) -> Result<(), String> {
+        let max_speed = match from.sog.into_iter().chain(to.sog).reduce(f64::max) {
+            Some(sog) => (sog * 1.5 + 5.0).min(self.config.max_speed),
+            None => self.config.max_speed,
+        };
+        let seconds = seconds_between(from, to);
+        let distance = track::distance(from.latitude, from.longitude, to.latitude, to.longitude);
+        let allowed = max_speed * METRES_PER_SECOND_PER_KNOT * seconds + 2.0 * self.config.accuracy;
+        if distance <= allowed {
+            return Ok(());
+        }
+        Err(format!(
+            "{:.0} m in {:.0} s is {:.1} kn, more than {:.1} kn",
+            distance,
+            seconds,
+            distance / seconds / METRES_PER_SECOND_PER_KNOT,
+            max_speed
+        ))
+    }
+
+    // Predict the position from the previous estimate using SOG and COG, then weigh the
+    // prediction and the fix by their variance. Both axes are treated as independent.
+    fn smooth(&mut self, estimate: &Point, point: Point) -> Point {
+        let (Some(sog), Some(cog)) = (point.sog.or(estimate.sog), point.cog.or(estimate.cog))
+        else {
+            // Without a velocity the prediction is worthless, so follow the fix
+            self.variance = self.config.accuracy.powi(2);
+            return point;
+        };
+        let seconds = seconds_between(estimate, &point);
+        let distance = sog * METRES_PER_SECOND_PER_KNOT * seconds;
+        let metres_per_degree_longitude = METRES_PER_DEGREE * estimate.latitude.to_radians().cos();
+        let latitude = estimate.latitude + distance * cog.to_radians().cos() / METRES_PER_DEGREE;
+        let longitude =
+            estimate.longitude + distance * cog.to_radians().sin() / metres_per_degree_longitude;
+        self.variance += (VELOCITY_ERROR * seconds).powi(2);
+
+        let gain = self.variance / (self.variance + self.config.accuracy.powi(2));
+        self.variance *= 1.0 - gain;
+        Point {
+            latitude: latitude + gain * (point.latitude - latitude),
+            longitude: longitude + gain * (point.longitude - longitude),
+            ..point
+        }
+    }
+}
+
+// At least one second, fixes can arrive with the same or even an older timestamp.
+fn seconds_between(from: &Point, to: &Point) -> f64 {
+    ((to.timestamp - from.timestamp).num_milliseconds() as f64 / 1000.0).max(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LATITUDE: f64 = 53.0;
+    const LONGITUDE: f64 = 5.0;
+
+    fn filter() -> PositionFilter {
+        PositionFilter::new(FilterConfig::from_config(&HashMap::new()).unwrap())
+    }
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        "2025-05-15T12:00:00Z".parse::<DateTime<Utc>>().unwrap()
+            + chrono::Duration::seconds(seconds)
+    }
+
+    // A position `metres` north of the start.
+    fn north(metres: f64) -> Option<f64> {
+        Some(LATITUDE + metres / METRES_PER_DEGREE)
+    }
+
+    #[test]
+    fn test_invalid_positions() {
+        let mut filter = filter();
+        assert_eq!(
+            filter.filter(None, Some(LONGITUDE), at(0), None, None),
+            None
+        );
+        assert_eq!(
+            filter.filter(Some(91.0), Some(LONGITUDE), at(0), None, None),
+            None
+        );
+        assert_eq!(filter.filter(Some(0.0), Some(0.0), at(0), None, None), None);
+    }
+
+    #[test]
+    fn test_speed_gate() {
+        let mut filter = filter();
+        let sog = Some(6.0);
+        assert!(
+            filter
+                .filter(north(0.0), Some(LONGITUDE), at(0), sog, None)
+                .is_some()
+        );
+        // At 6 knots up to 14 knots and twice the accuracy is allowed, that is 482 m a minute
+        assert!(
+            filter
+                .filter(north(185.0), Some(LONGITUDE), at(60), sog, None)
+                .is_some()
+        );
+        assert!(
+            filter
+                .filter(north(600.0), Some(LONGITUDE), at(120), sog, None)
+                .is_some()
+        );
+        // A jump of a kilometre in a minute is not
+        assert_eq!(
+            filter.filter(north(1700.0), Some(LONGITUDE), at(180), sog, None),
+            None
+        );
+        assert_eq!(filter.rejected, 1);
+        // The next fix is checked against the last good fix
+        assert!(
+            filter
+                .filter(north(900.0), Some(LONGITUDE), at(240), sog, None)
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_max_speed() {
+        // Without SOG the vessel may move at 50 knots, which is 1543 m a minute
+        let mut filter = filter();
+        assert!(
+            filter
+                .filter(north(0.0), Some(LONGITUDE), at(0), None, None)
+                .is_some()
+        );
+        assert!(
+            filter
+                .filter(north(1500.0), Some(LONGITUDE), at(60), None, None)
+                .is_some()
+        );
+        assert_eq!(
+            filter.filter(north(3100.0), Some(LONGITUDE), at(120), None, None),
+            None
+        );
+    }
+
+    #[test]
+    fn test_accept_jump_after_consistent_fixes() {
+        let mut filter = filter();
+        let longitude = Some(LONGITUDE);
+        assert!(
+            filter
+                .filter(north(0.0), longitude, at(0), None, None)
+                .is_some()
+        );
+        // The vessel shows up 50 km away, for instance after a trip on a trailer
+        let jump = 50_000.0;
+        assert_eq!(
+            filter.filter(north(jump), longitude, at(10), None, None),
+            None
+        );
+        assert_eq!(
+            filter.filter(north(jump + 5.0), longitude, at(20), None, None),
+            None
+        );
+        assert_eq!(
+            filter.filter(north(jump + 10.0), longitude, at(30), None, None),
+            Some((north(jump + 10.0).unwrap(), LONGITUDE))
+        );
+        // And the track continues from the new position
+        assert!(
+            filter
+                .filter(north(jump + 15.0), longitude, at(40), None, None)
+                .is_some()
+        );
+        assert_eq!(filter.rejected, 3);
+    }
+
+    #[test]
+    fn test_inconsistent_jumps_are_not_accepted() {
+        let mut filter = filter();
+        let longitude = Some(LONGITUDE);
+        assert!(
+            filter
+                .filter(north(0.0), longitude, at(0), None, None)
+                .is_some()
+        );
+        assert_eq!(
+            filter.filter(north(50_000.0), longitude, at(10), None, None),
+            None
+        );
+        assert_eq!(
+            filter.filter(north(-50_000.0), longitude, at(20), None, None),
+            None
+        );
+        assert_eq!(
+            filter.filter(north(50_000.0), longitude, at(30), None, None),
+            None
+        );
+        assert!(
+            filter
+                .filter(north(10.0), longitude, at(40), None, None)
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_kalman_smoothing() {
+        let general = HashMap::from([("position_smoothing".to_string(), "kalman".to_string())]);
+        let mut filter = PositionFilter::new(FilterConfig::from_config(&general).unwrap());
+        // Sailing north at 10 knots, a fix that is 20 m off to the east is pulled back
+        let sog = Some(10.0);
+        let cog = Some(0.0);
+        let metres_per_second = 10.0 * METRES_PER_SECOND_PER_KNOT;
+        for i in 0..10 {
+            let latitude = north(metres_per_second * i as f64 * 10.0);
+            filter.filter(latitude, Some(LONGITUDE), at(i * 10), sog, cog);
+        }
+        let east = 20.0 / (METRES_PER_DEGREE * LATITUDE.to_radians().cos());
+        let latitude = north(metres_per_second * 100.0);
+        let (_, longitude) = filter
+            .filter(latitude, Some(LONGITUDE + east), at(100), sog, cog)
+            .unwrap();
+        assert!(longitude > LONGITUDE && longitude < LONGITUDE + east / 2.0);
+    }
+
+    #[test]
+    fn test_config() {
+        let general = HashMap::from([("position_max_speed".to_string(), "-1".to_string())]);
+        assert!(FilterConfig::from_config(&general).is_err());
+        let general = HashMap::from([("position_smoothing".to_string(), "median".to_string())]);
+        assert!(FilterConfig::from_config(&general).is_err());
+    }
+}
@@ -0,0 +1,102 @@
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+
+use common::NetworkEndpoint;
+
+use crate::nmea;
+use crate::track::METRES_PER_SECOND_PER_KNOT;
+
+/// Turns the JSON reports of a gpsd provider into RMC sentences, so that fixes from gpsd
+/// go through the same location logic as those from a NMEA 0183 provider.
+///
+/// gpsd://127.0.0.1:2947?max_hdop=5
+///
+/// Only TPV reports with a 2D or 3D fix are used. When `max_hdop` is set, fixes are dropped
+/// while the HDOP in the last SKY report is higher than that.
+pub struct GpsdInput {
+    max_hdop: Option<f64>,
+    hdop: Option<f64>,
+}
+
+impl GpsdInput {
+    pub fn new(provider: &NetworkEndpoint) -> Self {
+        let max_hdop = provider.options.get("max_hdop").and_then(|v| {
+            v.parse::<f64>()
+                .map_err(|e| log::warn!("Ignoring invalid max_hdop '{}': {}", v, e))
+                .ok()
+        });
+        GpsdInput {
+            max_hdop,
+            hdop: None,
+        }
+    }
+
+    pub fn translate(&mut self, line: &str) -> String {
+        let report = match serde_json::from_str::<Value>(line) {
+            Ok(report) => report,
+            Err(e) => {
+                log::warn!("Invalid gpsd report '{}': {}", line.trim_end(), e);
+                return String::new();
+            }
+        };
+        match report.get("class").and_then(Value::as_str) {
+            Some("SKY") => {
+                if let Some(hdop) = report.get("hdop").and_then(Value::as_f64) {
+                    self.hdop = Some(hdop);
+                }
+                String::new()
+            }
+            Some("TPV") => self.tpv(&report).unwrap_or_default(),
+            _ => String::new(),
+        }
+    }
+
+    /// HDOP of the last SKY report.
+    pub fn hdop(&self) -> Option<f64> {
+        self.hdop
+    }
+
+    fn tpv(&self, report: &Value) -> Option<String> {
+        // 0 = unknown, 1 = no fix, 2 = 2D, 3 = 3D
+        let mode = report.get("mode").and_then(Value::as_u64).unwrap_or(0);
+        if mode < 2 {
+            log::trace!("Ignoring TPV report without fix");
+            return None;
+        }
+        if let (Some(hdop), Some(max_hdop)) = (self.hdop, self.max_hdop)
+            && hdop > max_hdop
+        {
+            log::debug!("Ignoring fix with HDOP {} > {}", hdop, max_hdop);
+            return None;
+        }
+        let latitude = report.get("lat").and_then(Value::as_f64)?;
+        let longitude = report.get("lon").and_then(Value::as_f64)?;
+        let timestamp = report
+            .get("time")
+            .and_then(Value::as_str)
+            .and_then(|ts| DateTime::parse_from_rfc3339(ts).ok())
+            .map(|ts| ts.with_timezone(&Utc))
+            .unwrap_or_else(Utc::now);
+        let sog = report
+            .get("speed")
+            .and_then(Value::as_f64)
+            .map(|v| v / METRES_PER_SECOND_PER_KNOT);
+        let cog = report.get("track").and_then(Value::as_f64);
+        // gpsd status 2 means the fix is DGPS corrected
+        let fix_mode = match report.get("status").and_then(Value::as_u64) {
+            Some(2) => 'D',
+            _ => 'A',
+        };
+        log::debug!(
+            "gpsd fix: mode {}{} HDOP {:?} {} {}",
+            mode,
+            fix_mode,
+            self.hdop,
+            latitude,
+            longitude
+        );
+        Some(nmea::rmc(
+            timestamp, latitude, longitude, sog, cog, fix_mode,
+        ))
+    }
+}
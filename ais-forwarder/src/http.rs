@@ -0,0 +1,190 @@
+use std::io;
+use std::time::Duration;
+
+use common::NetworkEndpoint;
+
+use crate::report::{self, LocationReport};
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+// POST a location report to a HTTP(S) endpoint.
+// The endpoint options select the body format (`format=json` or `format=form`) and how often
+// a request is retried when the server answers with a 5xx status (`retries=3`).
+// With `format=osmand` the report is sent using the OsmAnd protocol as understood by Traccar,
+// where the fields are passed in the query string and the vessel id is the device id.
+// Only a 2xx answer counts as delivered; in all other cases an error is returned so that the
+// caller keeps the message in persistence.
+pub fn send_message_http(
+    nmea_message: &[u8],
+    key: &str,
+    address: &NetworkEndpoint,
+) -> io::Result<()> {
+    let report = LocationReport::from_message(nmea_message, key)?;
+    let retries = address.parse_option::<u32>(key, "retries", "3")?;
+
+    let url = address.url();
+    let mut attempt = 0;
+    loop {
+        let request = ureq::post(&url).timeout(HTTP_TIMEOUT);
+        let result = match address.option("format", "json") {
+            "json" => request
+                .set("Content-Type", "application/json")
+                .send_string(&to_json(&report).to_string()),
+            "form" => {
+                let form = to_form(&report);
+                let form = form
+                    .iter()
+                    .map(|(k, v)| (*k, v.as_str()))
+                    .collect::<Vec<_>>();
+                request.send_form(&form)
+            }
+            "osmand" => to_osmand(&report)
+                .iter()
+                .fold(request, |request, (k, v)| request.query(k, v))
+                .call(),
+            format => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} ({}): unknown format '{}'", key, address, format),
+                ));
+            }
+        };
+
+        match result {
+            Ok(response) if (200..300).contains(&response.status()) => {
+                log::debug!(
+                    "{}: Sent message to {}: {}",
+                    key,
+                    address,
+                    response.status()
+                );
+                return Ok(());
+            }
+            Ok(response) => {
+                return Err(io::Error::other(format!(
+                    "send_message http {} ({}): unexpected status {} {}",
+                    key,
+                    address,
+                    response.status(),
+                    response.status_text()
+                )));
+            }
+            Err(ureq::Error::Status(code, _)) if code >= 500 && attempt < retries => {
+                attempt += 1;
+                log::warn!(
+                    "{}: {} answered {}, retry {} of {}",
+                    key,
+                    address,
+                    code,
+                    attempt,
+                    retries
+                );
+                std::thread::sleep(Duration::from_secs(attempt as u64));
+            }
+            Err(ureq::Error::Status(code, response)) => {
+                return Err(io::Error::other(format!(
+                    "send_message http {} ({}): status {} {}",
+                    key,
+                    address,
+                    code,
+                    response.status_text()
+                )));
+            }
+            Err(e) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("send_message http {} ({}): {}", key, address, e),
+                ));
+            }
+        }
+    }
+}
+
+fn to_json(report: &LocationReport) -> serde_json::Value {
+    // The JSON encoding takes care of any character, so send the id as configured
+    serde_json::json!({
+        "id": report::unescape_id(&report.id),
+        "time": report.timestamp.map(|ts| ts.to_rfc3339()),
+        "lat": report.latitude,
+        "lon": report.longitude,
+        "sog": report.sog,
+        "cog": report.cog,
+        "heading": report.heading,
+        "nav_status": report.nav_status,
+        "rot": report.rot,
+        "nmea": report.nmea,
+    })
+}
+
+fn to_form(report: &LocationReport) -> Vec<(&'static str, String)> {
+    // send_form encodes the values, so pass the id as configured
+    let mut form = vec![
+        ("id", report::unescape_id(&report.id)),
+        ("lat", report.latitude.to_string()),
+        ("lon", report.longitude.to_string()),
+        ("nmea", report.nmea.clone()),
+    ];
+    if let Some(ts) = report.timestamp {
+        form.push(("time", ts.to_rfc3339()));
+    }
+    if let Some(sog) = report.sog {
+        form.push(("sog", sog.to_string()));
+    }
+    if let Some(cog) = report.cog {
+        form.push(("cog", cog.to_string()));
+    }
+    if let Some(heading) = report.heading {
+        form.push(("heading", heading.to_string()));
+    }
+    if let Some(nav_status) = report.nav_status {
+        form.push(("nav_status", nav_status.to_string()));
+    }
+    if let Some(rot) = report.rot {
+        form.push(("rot", rot.to_string()));
+    }
+    form
+}
+
+// OsmAnd protocol: https://www.traccar.org/osmand/
+fn to_osmand(report: &LocationReport) -> Vec<(&'static str, String)> {
+    let timestamp = report.timestamp.unwrap_or_else(chrono::Utc::now);
+    // The query string is encoded by ureq, so pass the id as configured
+    let mut query = vec![
+        ("id", report::unescape_id(&report.id)),
+        ("lat", report.latitude.to_string()),
+        ("lon", report.longitude.to_string()),
+        ("timestamp", timestamp.timestamp().to_string()),
+    ];
+    if let Some(sog) = report.sog {
+        query.push(("speed", sog.to_string())); // Traccar expects knots
+    }
+    if let Some(cog) = report.cog {
+        query.push(("bearing", cog.to_string()));
+    }
+    query
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGE: &[u8] =
+        b"Sea%20Breeze$GNRMC,123519.00,A,5301.12345,N,00524.12345,E,5.2,181.0,150525,,,A*00";
+
+    #[test]
+    fn test_unescaped_id() {
+        let report = LocationReport::from_message(MESSAGE, "test").unwrap();
+        assert_eq!(to_json(&report)["id"], "Sea Breeze");
+        assert_eq!(to_form(&report)[0], ("id", "Sea Breeze".to_string()));
+        assert_eq!(to_osmand(&report)[0], ("id", "Sea Breeze".to_string()));
+    }
+
+    #[test]
+    fn test_osmand() {
+        let report = LocationReport::from_message(MESSAGE, "test").unwrap();
+        let query = to_osmand(&report);
+        assert_eq!(query[3], ("timestamp", "1747312519".to_string()));
+        assert_eq!(query[4], ("speed", "5.2".to_string()));
+        assert_eq!(query[5], ("bearing", "181".to_string()));
+    }
+}
@@ -1,71 +1,102 @@
 /// (C) 2025 by Kees Verruijt, Harlingen, Netherlands
+use chrono::{DateTime, Utc};
 use nmea_parser::ParsedMessage;
 use std::collections::HashMap;
-use std::io;
+use std::io::{self, BufRead};
 use std::sync::mpsc::Receiver;
 use std::time::Duration;
 
+use common::Protocol;
+use common::ack;
+use common::sign;
+
 use crate::cache::Persistence;
+use crate::filter::{FilterConfig, PositionFilter};
+use crate::nmea;
+use crate::report::LocationReport;
+use crate::track::{self, TrackPoint};
 use crate::{NetworkEndpoint, send_message};
 
+const DECIMATE_DEFAULT_TOLERANCE: &str = "25";
+const DECIMATE_DEFAULT_GAP: &str = "3600";
+const ACK_BATCH_SIZE: usize = 100;
+const COMPRESSED_BATCH_SIZE: usize = 5000;
+const ACK_TIMEOUT: Duration = Duration::from_secs(30);
+// A message that the receiver rejects this many times is dropped from the queue.
+const MAX_REJECTIONS: u32 = 3;
+
+// Number of times the receiver rejected each queued message of an endpoint.
+type Rejections = HashMap<sled::IVec, u32>;
+
+/// A position message for the location thread, with the time of the fix. This is the time
+/// in the message itself when it has one, otherwise the time the message was received.
+#[derive(Debug)]
+pub struct LocationUpdate {
+    pub message: ParsedMessage,
+    pub timestamp: DateTime<Utc>,
+    pub mode: char, // NMEA 2.3 mode indicator of the fix
+    pub hdop: Option<f64>,
+}
+
 pub fn work_thread(
-    rx: std::sync::mpsc::Receiver<ParsedMessage>,
+    rx: Receiver<LocationUpdate>,
     location: HashMap<String, NetworkEndpoint>,
+    vessel_id: String,
     mmsi: u32,
-    cache_dir: &str,
+    persistence: Persistence,
+    filter: FilterConfig,
 ) {
-    let persistence = Persistence::new(cache_dir);
-
-    let _ = Location::new(location, persistence, mmsi).location_loop(&rx);
+    let _ = Location::new(location, persistence, vessel_id, mmsi, filter).location_loop(&rx);
 }
 
 struct Location {
     location: HashMap<String, NetworkEndpoint>,
     persistence: Persistence,
-    mmsi: u32,
-    prev_latitude: Option<f64>,
-    prev_longitude: Option<f64>,
-    doubtful_latitude: Option<f64>,
-    doubtful_longitude: Option<f64>,
+    vessel_id: String,
+    mmsi: u32, // Signal K identifies vessels by MMSI, not by the vessel id
+    filter: PositionFilter,
+    sequence: u64,
+    rejections: HashMap<String, Rejections>,
 }
 
 impl Location {
     fn new(
         location: HashMap<String, NetworkEndpoint>,
         persistence: Persistence,
+        vessel_id: String,
         mmsi: u32,
+        filter: FilterConfig,
     ) -> Self {
         Self {
             location,
             persistence,
+            vessel_id,
             mmsi,
-            prev_latitude: None,
-            prev_longitude: None,
-            doubtful_latitude: None,
-            doubtful_longitude: None,
+            filter: PositionFilter::new(filter),
+            sequence: 0,
+            rejections: HashMap::new(),
         }
     }
 
-    fn location_loop(&mut self, rx: &Receiver<ParsedMessage>) -> io::Result<()> {
+    fn location_loop(&mut self, rx: &Receiver<LocationUpdate>) -> io::Result<()> {
         const MESSAGE_TIMEOUT: Duration = Duration::from_secs(360);
 
         log::info!(
             "Starting location loop with {} endpoints",
             self.location.len()
         );
-        // Keep track of whether we are able to send messages to the server
+        // Keep track of whether we are able to send messages to all servers
         let mut connection_ok = self.resend_messages().is_ok();
         let mut first = true;
 
         loop {
             match rx.recv_timeout(MESSAGE_TIMEOUT) {
-                Ok(message) => {
-                    log::debug!("Received message: {:?}", message);
+                Ok(update) => {
+                    log::debug!("Received message: {:?}", update);
                     if !connection_ok {
                         first = true;
-                        connection_ok = self.resend_messages().is_ok();
                     }
-                    connection_ok = self.parse_message(&message, connection_ok).is_ok();
+                    connection_ok = self.parse_message(&update).is_ok();
                     if first {
                         log::info!(
                             "Location thread sent first message, connection ok: {}",
@@ -94,181 +125,477 @@ impl Location {
         }
     }
 
+    // Drain the queue of every endpoint, an endpoint that is down does not hold up the others.
     fn resend_messages(&mut self) -> io::Result<()> {
-        let resend_count = self.persistence.count();
+        let mut result = Ok(());
+        for (key, address) in self.location.iter_mut() {
+            if let Err(e) = Self::resend_queue(
+                &mut self.persistence,
+                key,
+                address,
+                self.rejections.entry(key.clone()).or_default(),
+                &mut self.sequence,
+            ) {
+                log::error!("Error resending location messages to {}: {}", key, e);
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    // Once the first cached message is delivered the rest of the backlog is thinned out
+    // to the `decimate` tolerance in metres before it is sent, use `decimate=0` to send the
+    // backlog at full resolution. See `track::simplify`.
+    fn resend_queue(
+        persistence: &mut Persistence,
+        key: &str,
+        address: &mut NetworkEndpoint,
+        rejections: &mut Rejections,
+        sequence: &mut u64,
+    ) -> io::Result<()> {
+        let resend_count = persistence.count(key);
         if resend_count == 0 {
-            log::info!("No messages to resend from persistence");
+            log::debug!("{}: No messages to resend from persistence", key);
             return Ok(());
         }
-        log::info!("Resending {} messages from persistence", resend_count);
-        for item in self.persistence.iter() {
-            match item {
-                Ok((key, value)) => {
-                    let key = &key.to_vec();
-                    let value = &value.to_vec();
-                    let skey = String::from_utf8_lossy(&key);
-                    let svalue = String::from_utf8_lossy(&value);
-                    log::debug!("Resending message: {}: {}", skey, svalue);
-                    for (key, address) in self.location.iter_mut() {
-                        send_message(value, key, address)?;
-                    }
-                    self.persistence.remove(key);
-                    self.persistence.flush();
-                }
-                Err(e) => {
-                    log::error!("Error reading from database: {}", e);
-                }
+        let tolerance = address.parse_option::<f64>(key, "decimate", DECIMATE_DEFAULT_TOLERANCE)?;
+        let max_gap = address.parse_option::<i64>(key, "decimate_gap", DECIMATE_DEFAULT_GAP)?;
+        log::info!(
+            "{}: Resending {} messages from persistence",
+            key,
+            resend_count
+        );
+
+        let backlog = persistence
+            .iter(key)
+            .filter_map(|item| {
+                item.map_err(|e| log::error!("Error reading from database: {}", e))
+                    .ok()
+            })
+            .collect::<Vec<_>>();
+        if tolerance <= 0.0 || backlog.len() <= 2 {
+            return Self::deliver(persistence, key, address, &backlog, rejections, sequence);
+        }
+        // A message that the receiver rejects does not stop the rest of the backlog
+        match Self::deliver(
+            persistence,
+            key,
+            address,
+            &backlog[..1],
+            rejections,
+            sequence,
+        ) {
+            Err(e) if e.kind() != io::ErrorKind::InvalidData => return Err(e),
+            _ => {}
+        }
+
+        // The connection works, thin out the rest of the backlog
+        let backlog = &backlog[1..];
+        let points = backlog
+            .iter()
+            .map(|(_, value)| {
+                LocationReport::parse(&String::from_utf8_lossy(value)).map(|report| TrackPoint {
+                    latitude: report.latitude,
+                    longitude: report.longitude,
+                    timestamp: report.timestamp,
+                })
+            })
+            .collect::<Vec<_>>();
+        let track = points.iter().flatten().copied().collect::<Vec<_>>();
+        let mut keep = track::simplify(&track, tolerance, max_gap).into_iter();
+        let mut kept = Vec::new();
+        for (entry, point) in backlog.iter().zip(points.iter()) {
+            // Messages that are not a position report are always sent
+            if point.is_some() && keep.next() == Some(false) {
+                persistence.remove(key, &entry.0);
+            } else {
+                kept.push(entry.clone());
             }
         }
-        Ok(())
+        log::info!(
+            "{}: Resending {} of {} messages, the rest is within {} m of the track",
+            key,
+            kept.len() + 1,
+            resend_count,
+            tolerance
+        );
+        Self::deliver(persistence, key, address, &kept, rejections, sequence)
     }
 
-    fn validate_position(&mut self, latitude: Option<f64>, longitude: Option<f64>) -> bool {
-        if latitude.is_none() || longitude.is_none() {
-            log::warn!("Invalid position: latitude or longitude is None");
-            return false;
-        }
-        let latitude = latitude.unwrap();
-        let longitude = longitude.unwrap();
-        let latitude_abs = latitude.abs();
-        let longitude_abs = longitude.abs();
-        if latitude_abs > 90.0 || longitude_abs > 180.0 {
-            log::warn!("Invalid position: latitude or longitude out of range");
-            return false;
+    // Send queued messages and remove them from the queue once they are delivered. With the
+    // `ack=true` option messages are sent in batches and only removed once the receiver has
+    // acknowledged them, see `common::ack`. With `compress=gzip` a backlog is sent as
+    // compressed batches, see `common::batch`.
+    fn deliver(
+        persistence: &mut Persistence,
+        key: &str,
+        address: &mut NetworkEndpoint,
+        entries: &[(sled::IVec, sled::IVec)],
+        rejections: &mut Rejections,
+        sequence: &mut u64,
+    ) -> io::Result<()> {
+        let compress = match address.option("compress", "none") {
+            "none" => false,
+            "gzip" if matches!(address.protocol, Protocol::TCP) => true,
+            compress => return Err(address.invalid_option(key, "compress", compress)),
+        };
+        let batch_size = match compress {
+            true => COMPRESSED_BATCH_SIZE,
+            false => ACK_BATCH_SIZE,
+        };
+        let result = match address.option("ack", "false") {
+            "true" => {
+                let mut rejected = 0;
+                entries
+                    .chunks(batch_size)
+                    .try_for_each(|batch| {
+                        rejected += Self::deliver_batch(
+                            persistence,
+                            key,
+                            address,
+                            batch,
+                            rejections,
+                            sequence,
+                            compress,
+                        )?;
+                        Ok(())
+                    })
+                    .and_then(|_| match rejected {
+                        0 => Ok(()),
+                        _ => Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "{} ({}): {} messages were rejected by the receiver",
+                                key, address, rejected
+                            ),
+                        )),
+                    })
+            }
+            _ if compress && entries.len() > 1 => {
+                entries.chunks(batch_size).try_for_each(|batch| {
+                    let lines = batch
+                        .iter()
+                        .map(|(_, value)| Self::sign(address, &String::from_utf8_lossy(value)))
+                        .collect::<String>();
+                    let payload = common::batch::encode(&lines)?;
+                    send_message(&payload, key, address)?;
+                    log::debug!(
+                        "{}: Sent {} messages in {} bytes",
+                        key,
+                        batch.len(),
+                        payload.len()
+                    );
+                    for (db_key, _) in batch {
+                        persistence.remove(key, db_key);
+                    }
+                    Ok(())
+                })
+            }
+            _ => entries.iter().try_for_each(|(db_key, value)| {
+                let skey = String::from_utf8_lossy(db_key);
+                let svalue = String::from_utf8_lossy(value);
+                log::debug!("Resending message: {}: {}", skey, svalue);
+                let signed = Self::sign(address, &svalue);
+                send_message(signed.as_bytes(), key, address)?;
+                persistence.remove(key, db_key);
+                Ok(())
+            }),
+        };
+        persistence.flush();
+        result
+    }
+
+    fn deliver_batch(
+        persistence: &mut Persistence,
+        key: &str,
+        address: &mut NetworkEndpoint,
+        batch: &[(sled::IVec, sled::IVec)],
+        rejections: &mut Rejections,
+        sequence: &mut u64,
+        compress: bool,
+    ) -> io::Result<usize> {
+        if !matches!(address.protocol, Protocol::TCP) {
+            return Err(address.invalid_option(key, "ack", "only supported for tcp"));
         }
-        if latitude_abs < 0.01 || longitude_abs < 0.01 {
-            log::warn!("Invalid position: latitude and longitude are too close to zero");
-            return false;
+        // Every line gets its own sequence number, a message is removed from the queue once
+        // all its lines are stored. Messages with a rejected line stay in the queue, until
+        // they have been rejected `MAX_REJECTIONS` times.
+        let mut pending = HashMap::new();
+        let mut outstanding = vec![0; batch.len()];
+        let mut rejected = vec![false; batch.len()];
+        let mut lines = String::new();
+        for (i, (_, value)) in batch.iter().enumerate() {
+            let message = Self::sign(address, &String::from_utf8_lossy(value));
+            for line in message.lines().filter(|line| !line.is_empty()) {
+                *sequence += 1;
+                lines.push_str(&ack::sequenced(*sequence, line));
+                pending.insert(*sequence, i);
+                outstanding[i] += 1;
+            }
         }
-        if let Some(prev_latitude) = self.prev_latitude {
-            if (latitude - prev_latitude).abs() >= 2.00 {
-                if let Some(doubtful_latitude) = self.doubtful_latitude {
-                    if (latitude - doubtful_latitude).abs() >= 2.00 {
-                        log::warn!("Doubtful position: latitude change is too big");
-                        return false;
-                    }
-                } else {
-                    log::warn!("Invalid position: latitude change is too big");
-                    return false;
+        let payload = match compress {
+            true => common::batch::encode(&lines)?,
+            false => lines.into_bytes(),
+        };
+        send_message(&payload, key, address)?;
+        log::debug!("{}: Sent {} messages, waiting for ack", key, batch.len());
+
+        let result = Self::wait_for_acks(address, |first, last, stored| {
+            for sequence in first..=last {
+                let Some(i) = pending.remove(&sequence) else {
+                    continue;
+                };
+                outstanding[i] -= 1;
+                rejected[i] |= !stored;
+                if outstanding[i] == 0 && !rejected[i] {
+                    persistence.remove(key, &batch[i].0);
+                    rejections.remove(&batch[i].0);
                 }
             }
+            pending.is_empty()
+        });
+        if let Err(e) = result {
+            // Start over on a new connection, unacknowledged messages are sent again
+            address.tcp_stream.clear();
+            return Err(io::Error::new(
+                e.kind(),
+                format!(
+                    "{} ({}): no ack for {} lines: {}",
+                    key,
+                    address,
+                    pending.len(),
+                    e
+                ),
+            ));
+        }
+        let mut dropped = 0;
+        for ((db_key, _), _) in batch
+            .iter()
+            .zip(&rejected)
+            .filter(|(_, rejected)| **rejected)
+        {
+            let count = rejections.entry(db_key.clone()).or_default();
+            *count += 1;
+            if *count >= MAX_REJECTIONS {
+                persistence.remove(key, db_key);
+                rejections.remove(db_key);
+                dropped += 1;
+            }
         }
-        if let Some(prev_longitude) = self.prev_longitude {
-            if (longitude - prev_longitude).abs() >= 2.00 {
-                if let Some(doubtful_longitude) = self.doubtful_longitude {
-                    if (longitude - doubtful_longitude).abs() >= 2.00 {
-                        log::warn!("Doubtful position: longitude change is too big");
-                        return false;
+        if dropped > 0 {
+            log::error!(
+                "{}: Dropped {} messages that the receiver rejected {} times",
+                key,
+                dropped,
+                MAX_REJECTIONS
+            );
+        }
+        let rejected = rejected.iter().filter(|&&rejected| rejected).count() - dropped;
+        if rejected > 0 {
+            log::error!(
+                "{}: Receiver rejected {} messages, they stay in the queue",
+                key,
+                rejected
+            );
+        }
+        Ok(rejected)
+    }
+
+    // With the `secret=...` option every line sent to a TCP or UDP endpoint is signed with
+    // the time of sending, so a location-receiver can reject forged and replayed reports.
+    // See `common::sign`.
+    fn sign(address: &NetworkEndpoint, message: &str) -> String {
+        match address.options.get("secret") {
+            Some(secret) if matches!(address.protocol, Protocol::TCP | Protocol::UDP) => {
+                let now = chrono::Utc::now().timestamp() as u64;
+                sign::sign(secret, now, message)
+            }
+            _ => message.to_string(),
+        }
+    }
+
+    // Read ACK and NAK lines until `acked` returns true for a range.
+    fn wait_for_acks<F>(address: &mut NetworkEndpoint, mut acked: F) -> io::Result<()>
+    where
+        F: FnMut(u64, u64, bool) -> bool,
+    {
+        let stream = address
+            .tcp_stream
+            .get_mut(0)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "not connected"))?;
+        stream.set_read_timeout(Some(ACK_TIMEOUT))?;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if stream.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionReset,
+                    "connection closed",
+                ));
+            }
+            match ack::parse_reply(&line) {
+                Some((first, last, stored)) => {
+                    if acked(first, last, stored) {
+                        return Ok(());
                     }
-                } else {
-                    log::warn!("Invalid position: longitude change is too big");
-                    return false;
                 }
+                None => log::warn!("Ignoring unexpected reply '{}'", line.trim_end()),
             }
         }
-
-        true
     }
 
-    fn parse_message(&mut self, message: &ParsedMessage, connection_ok: bool) -> io::Result<()> {
-        let now = chrono::Utc::now();
-        const TIME_FORMAT: &str = "%H%M%S";
-        const DATE_FORMAT: &str = "%d%m%y";
+    fn parse_message(&mut self, update: &LocationUpdate) -> io::Result<()> {
+        let timestamp = update.timestamp;
 
-        let nmea_message = match message {
+        let (fix, source) = match &update.message {
             ParsedMessage::VesselDynamicData(message) => {
-                if !self.validate_position(message.latitude, message.longitude) {
-                    // If the same "weird" position is received a second time, we assume this
-                    // is the new ships position.
-                    self.doubtful_latitude = message.latitude;
-                    self.doubtful_longitude = message.longitude;
+                let Some((latitude, longitude)) = self.filter.filter(
+                    message.latitude,
+                    message.longitude,
+                    timestamp,
+                    message.sog_knots,
+                    message.cog,
+                ) else {
                     return Ok(());
-                }
-                self.prev_latitude = message.latitude;
-                self.prev_longitude = message.longitude;
-                self.doubtful_latitude = None;
-                self.doubtful_longitude = None;
-                format!(
-                    "{}$GNRMC,{},A,{},{},{},{},{},,,A\r\n",
-                    message.mmsi,
-                    now.format(TIME_FORMAT),
-                    Self::format_lat_long(message.latitude, true),
-                    Self::format_lat_long(message.longitude, false),
-                    "", // Speed over ground,
-                    "", // Course over ground,
-                    now.format(DATE_FORMAT),
-                )
+                };
+                let fix = nmea::Fix {
+                    timestamp,
+                    latitude,
+                    longitude,
+                    sog: message.sog_knots,
+                    cog: message.cog,
+                    variation: None,
+                    heading: message.heading_true,
+                    rot: message.rot,
+                    nav_status: Some(message.nav_status as u8),
+                    mode: update.mode,
+                    hdop: update.hdop,
+                };
+                (fix, "ais")
             }
             ParsedMessage::Rmc(message) => {
-                if !self.validate_position(message.latitude, message.longitude) {
-                    // If the same "weird" position is received a second time, we assume this
-                    // is the new ships position.
-                    self.doubtful_latitude = message.latitude;
-                    self.doubtful_longitude = message.longitude;
+                let Some((latitude, longitude)) = self.filter.filter(
+                    message.latitude,
+                    message.longitude,
+                    timestamp,
+                    message.sog_knots,
+                    message.bearing,
+                ) else {
                     return Ok(());
-                }
-                self.prev_latitude = message.latitude;
-                self.prev_longitude = message.longitude;
-                self.doubtful_latitude = None;
-                self.doubtful_longitude = None;
-                let ts = message.timestamp.unwrap_or(now);
-                format!(
-                    "{}$GNRMC,{},A,{},{},{},{},{},,,A\r\n",
-                    self.mmsi,
-                    ts.format(TIME_FORMAT),
-                    Self::format_lat_long(message.latitude, true),
-                    Self::format_lat_long(message.longitude, false),
-                    Self::format_option(message.sog_knots),
-                    Self::format_option(message.bearing),
-                    ts.format(DATE_FORMAT),
-                )
+                };
+                let fix = nmea::Fix {
+                    timestamp,
+                    latitude,
+                    longitude,
+                    sog: message.sog_knots,
+                    cog: message.bearing,
+                    variation: message.variation,
+                    heading: None,
+                    rot: None,
+                    nav_status: None,
+                    mode: update.mode,
+                    hdop: update.hdop,
+                };
+                (fix, "rmc")
             }
             _ => {
-                log::warn!("Unsupported message type: {:?}", message);
+                log::warn!("Unsupported message type: {:?}", update.message);
                 return Ok(());
             }
         };
 
-        let nmea_bytes = nmea_message.as_bytes();
+        // Keyed by the time of the fix, so a replayed backlog is stored and resent in track order.
+        // The record number keeps fixes within the same second apart.
+        let record = self.persistence.generate_id();
+        let db_key = format!("{} {:020}", timestamp, record);
+        // Messages are queued behind older messages for the same endpoint, so they are
+        // delivered in order.
+        let mut result = Ok(());
         for (key, address) in self.location.iter_mut() {
-            let db_key = format!("{}-{}", now, key);
-            if !connection_ok {
+            let id = match address.protocol {
+                Protocol::SignalKWs | Protocol::SignalKTcp => self.mmsi.to_string(),
+                _ => self.vessel_id.clone(),
+            };
+            let nmea_message = Self::format_report(&id, &fix, source, record, key, address);
+            let nmea_bytes = nmea_message.as_bytes();
+            if self.persistence.count(key) > 0 || address.option("ack", "false") == "true" {
                 log::debug!("Storing message: {}: {}", key, nmea_message);
-                self.persistence.store(db_key.as_bytes(), nmea_bytes);
+                self.persistence.store(key, db_key.as_bytes(), nmea_bytes);
                 self.persistence.flush();
+                if let Err(e) = Self::resend_queue(
+                    &mut self.persistence,
+                    key,
+                    address,
+                    self.rejections.entry(key.clone()).or_default(),
+                    &mut self.sequence,
+                ) {
+                    log::error!("Error sending location message to {}: {}", key, e);
+                    result = Err(e);
+                }
             } else {
                 log::debug!("Sending message: {}: {}", key, nmea_message);
-                if let Err(e) = send_message(&nmea_bytes, key, address) {
+                let signed = Self::sign(address, &nmea_message);
+                if let Err(e) = send_message(signed.as_bytes(), key, address) {
                     log::error!("Error sending location message to {}: {}", key, e);
-                    self.persistence.store(db_key.as_bytes(), nmea_bytes);
+                    self.persistence.store(key, db_key.as_bytes(), nmea_bytes);
                     self.persistence.flush();
+                    result = Err(e);
                 }
             }
         }
-        Ok(())
+        result
     }
 
-    fn format_option(value: Option<f64>) -> String {
-        match value {
-            Some(value) => format!("{:.1}", value),
-            None => "".to_string(),
+    // With `format=json` a TCP or UDP endpoint receives one JSON object per line. Otherwise
+    // every sentence is prefixed with the vessel id. The RMC sentence comes first, followed by
+    // the sentences in the `extra_sentences` option, for example `extra_sentences=gga,vtg,zda`,
+    // and with `extended=true` the heading, navigational status and rate of turn.
+    fn format_report(
+        id: &str,
+        fix: &nmea::Fix,
+        source: &str,
+        record: u64,
+        key: &str,
+        address: &NetworkEndpoint,
+    ) -> String {
+        if matches!(address.protocol, Protocol::TCP | Protocol::UDP)
+            && address.option("format", "nmea") == "json"
+        {
+            let json = serde_json::json!({
+                "id": id,
+                "time": fix.timestamp.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+                "lat": fix.latitude,
+                "lon": fix.longitude,
+                "sog": fix.sog,
+                "cog": fix.cog,
+                "heading": fix.heading,
+                "nav_status": fix.nav_status,
+                "rot": fix.rot,
+                "source": source,
+                "quality": fix.quality(),
+                "hdop": fix.hdop,
+                "seq": record,
+            });
+            return format!("{}\r\n", json);
         }
-    }
 
-    fn format_lat_long(latlon: Option<f64>, is_lat: bool) -> String {
-        match latlon {
-            Some(value) => {
-                let hemisphere = if is_lat {
-                    if value >= 0.0 { "N" } else { "S" }
-                } else {
-                    if value >= 0.0 { "E" } else { "W" }
-                };
-                let abs_value = value.abs();
-                let degrees = abs_value.trunc();
-                let minutes = (abs_value - degrees) * 60.0;
-                format!("{:.5},{}", degrees * 100.0 + minutes, hemisphere)
-            }
-            None => ",".to_string(),
+        let mut report = format!("{}{}", id, fix.rmc("GN"));
+        let extra_sentences = address.option("extra_sentences", "");
+        for sentence in extra_sentences.split(',').filter(|s| !s.is_empty()) {
+            let sentence = match sentence {
+                "gga" => fix.gga("GN"),
+                "vtg" => fix.vtg("GN"),
+                "zda" => fix.zda("GN"),
+                _ => {
+                    log::warn!("{}: Ignoring unknown sentence '{}'", key, sentence);
+                    continue;
+                }
+            };
+            report.push_str(&format!("{}{}", id, sentence));
+        }
+        if address.option("extended", "false") == "true" {
+            report.push_str(&format!("{}{}", id, fix.extended()));
         }
+        report
     }
 }
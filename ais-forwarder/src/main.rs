@@ -1,7 +1,9 @@
+use chrono::{DateTime, Timelike, Utc};
 use clap::Parser;
 use config::Config;
 use env_logger::Env;
 use nmea_parser::ParsedMessage;
+use nmea_parser::gnss::RmcData;
 use std::collections::HashMap;
 use std::net::UdpSocket;
 use std::ops::Add;
@@ -18,24 +20,63 @@ use common::buffer::BufReaderDirectWriter;
 use common::send_message_tcp;
 use common::send_message_udp;
 
+mod aivdm;
+mod aprs;
 mod cache;
+mod filter;
+mod gpsd;
+mod http;
 mod location;
+mod movement;
+mod mqtt;
+mod n2k;
+mod nmea;
+mod report;
+mod signalk;
+mod spool;
+mod track;
+
+use location::LocationUpdate;
 
 struct LastSent {
     vessel_dynamic_data: Instant,
     vessel_static_data: Instant,
 }
 
+// When our own location is due, kept across reconnects of the provider.
+struct LocationSchedule {
+    movement: movement::Movement,
+    next_location_ts: SystemTime,
+    next_location_anchor_ts: SystemTime,
+}
+
+impl LocationSchedule {
+    fn new(movement: movement::MovementConfig) -> Self {
+        // The first fix after start-up is always reported
+        LocationSchedule {
+            movement: movement::Movement::new(movement),
+            next_location_ts: SystemTime::UNIX_EPOCH,
+            next_location_anchor_ts: SystemTime::UNIX_EPOCH,
+        }
+    }
+}
+
 struct Dispatcher {
     provider: NetworkEndpoint,
     ais: HashMap<String, NetworkEndpoint>,
-    location_tx: Sender<ParsedMessage>,
+    location_tx: Sender<LocationUpdate>,
     interval: u64,
     location_interval: u64,
     location_anchor_interval: u64,
+    schedule: LocationSchedule,
     nmea_parser: nmea_parser::NmeaParser,
     last_sent: HashMap<u32, LastSent>,
     last_sent_location: SystemTime,
+    signalk: signalk::SignalKInput,
+    gpsd: gpsd::GpsdInput,
+    n2k: n2k::N2kInput,
+    class_b: Option<aivdm::Static>,
+    spools: HashMap<String, spool::Spool>,
 }
 
 #[derive(Parser, Clone, Debug)]
@@ -95,7 +136,17 @@ fn main() {
             exit(1);
         }
     };
-    log::info!("Settings: {:?}", settings);
+    let redacted = settings
+        .iter()
+        .map(|(section, values)| {
+            let values = values
+                .iter()
+                .map(|(key, value)| (key, common::redact_options(value)))
+                .collect::<HashMap<_, _>>();
+            (section, values)
+        })
+        .collect::<HashMap<_, _>>();
+    log::info!("Settings: {:?}", redacted);
 
     let general = match settings.get("general") {
         Some(internal) => internal,
@@ -115,6 +166,16 @@ fn main() {
             exit(1);
         }
     };
+    let vessel_id = match general.get("vessel_id") {
+        None => mmsi.to_string(),
+        Some(vessel_id) => match report::vessel_id(vessel_id) {
+            Ok(vessel_id) => vessel_id,
+            Err(e) => {
+                log::error!("Invalid vessel_id in config.ini: {}", e);
+                exit(1);
+            }
+        },
+    };
     let interval = match general.get("interval").map(|v| v.parse::<u64>()) {
         None => 60,
         Some(Ok(interval)) => interval,
@@ -143,7 +204,11 @@ fn main() {
         }
     };
 
-    let (tx, rx) = std::sync::mpsc::channel::<ParsedMessage>();
+    let class_b = settings
+        .get("class_b")
+        .map(|class_b| class_b_config(class_b, mmsi));
+
+    let (tx, rx) = std::sync::mpsc::channel::<LocationUpdate>();
     let location = match settings.get("location") {
         Some(location) => location,
         None => {
@@ -156,20 +221,49 @@ fn main() {
         let address = value
             .parse::<NetworkEndpoint>()
             .map_err(|e| {
-                log::error!("Invalid address '{}' in config.ini: {}", value, e);
+                log::error!(
+                    "Invalid address '{}' in config.ini: {}",
+                    common::redact_options(value),
+                    e
+                );
                 exit(1);
             })
             .unwrap();
         (key.clone(), address)
     })
     .collect();
+    // The AIS spools and the location thread share the same database
+    let movement = match movement::MovementConfig::from_config(general) {
+        Ok(movement) => movement,
+        Err(e) => {
+            log::error!("{} in config.ini", e);
+            exit(1);
+        }
+    };
+    let cache_limits = match cache::CacheLimits::from_config(general) {
+        Ok(cache_limits) => cache_limits,
+        Err(e) => {
+            log::error!("{} in config.ini", e);
+            exit(1);
+        }
+    };
+    let filter = match filter::FilterConfig::from_config(general) {
+        Ok(filter) => filter,
+        Err(e) => {
+            log::error!("{} in config.ini", e);
+            exit(1);
+        }
+    };
+    let persistence = cache::Persistence::new(&cli.cache_dir, cache_limits);
+    let location_persistence = persistence.clone();
     Builder::new()
         .name("location".to_string())
         .spawn(move || {
-            location::work_thread(rx, location, mmsi, cli.cache_dir.as_str());
+            location::work_thread(rx, location, vessel_id, mmsi, location_persistence, filter);
         })
         .unwrap();
 
+    let mut schedule = LocationSchedule::new(movement);
     loop {
         let provider = match general
             .get("provider")
@@ -199,38 +293,81 @@ fn main() {
                 let address = value
                     .parse::<NetworkEndpoint>()
                     .map_err(|e| {
-                        log::error!("Invalid address '{}' in config.ini: {}", value, e);
+                        log::error!(
+                            "Invalid address '{}' in config.ini: {}",
+                            common::redact_options(value),
+                            e
+                        );
                         exit(1);
                     })
                     .unwrap();
+                if let Protocol::HTTP | Protocol::HTTPS | Protocol::APRS = address.protocol {
+                    log::error!(
+                        "Invalid address '{}' in config.ini: {} is only supported for [location]",
+                        common::redact_options(value),
+                        address.protocol
+                    );
+                    exit(1);
+                }
+                if let Some(station) = address.options.get("station")
+                    && !nmea::is_valid_station(station)
+                {
+                    log::error!(
+                        "Invalid station '{}' in config.ini: it must not contain , * \\ or !",
+                        station
+                    );
+                    exit(1);
+                }
                 (key.clone(), address)
             })
+            .collect::<HashMap<_, _>>();
+        let spools = ais
+            .iter()
+            .filter(|(_, address)| address.option("spool", "false") == "true")
+            .map(
+                |(key, address)| match spool::Spool::new(&persistence, key, address) {
+                    Ok(spool) => (key.clone(), spool),
+                    Err(e) => {
+                        log::error!("Invalid address '{}' in config.ini: {}", address, e);
+                        exit(1);
+                    }
+                },
+            )
             .collect();
 
         let mut dispatcher = Dispatcher::new(
             provider,
             ais,
+            spools,
             tx.clone(),
             interval,
             location_interval,
             location_anchor_interval,
+            schedule,
+            class_b.clone(),
         );
         if let Err(e) = dispatcher.work() {
             log::error!("{}", e);
             std::thread::sleep(Duration::from_secs(1));
         }
+        schedule = dispatcher.schedule;
     }
 }
 
 impl Dispatcher {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         provider: NetworkEndpoint,
         ais: HashMap<String, NetworkEndpoint>,
-        location_tx: Sender<ParsedMessage>,
+        spools: HashMap<String, spool::Spool>,
+        location_tx: Sender<LocationUpdate>,
         interval: u64,
         location_interval: u64,
         location_anchor_interval: u64,
+        schedule: LocationSchedule,
+        class_b: Option<aivdm::Static>,
     ) -> Self {
+        let gpsd = gpsd::GpsdInput::new(&provider);
         Dispatcher {
             provider,
             ais,
@@ -238,9 +375,15 @@ impl Dispatcher {
             interval,
             location_interval,
             location_anchor_interval,
+            schedule,
             nmea_parser: nmea_parser::NmeaParser::new(),
             last_sent: HashMap::new(),
             last_sent_location: SystemTime::now() - Duration::from_secs(location_interval),
+            signalk: signalk::SignalKInput::new(),
+            gpsd,
+            n2k: n2k::N2kInput::new(),
+            class_b,
+            spools,
         }
     }
 
@@ -275,26 +418,34 @@ impl Dispatcher {
 
         let mut fragments = Vec::new();
         let mut last_seen_rmc_message = SystemTime::UNIX_EPOCH;
-        let mut prev_lat = 0.0;
-        let mut prev_long = 0.0;
-        let now = SystemTime::now();
-        let mut next_location_ts = self.next_location_system_time(&now);
-        let mut next_location_anchor_ts = self.next_location_anchor_system_time(&now);
 
         loop {
             log::trace!("Waiting for message from provider");
             let message = self.provider.read_to_string()?;
+            let received = Utc::now();
             log::trace!("Received message: {}", message);
+            let message = match self.provider.protocol {
+                Protocol::SignalKWs => self.signalk.translate(&message),
+                Protocol::Gpsd => self.gpsd.translate(&message),
+                Protocol::N2kJson => self.n2k.translate(&message),
+                _ => message,
+            };
 
             for line in message.lines() {
                 log::trace!("Received line: {}", line);
-                match self.nmea_parser.parse_sentence(line) {
+                // Tag blocks are kept on the line that is forwarded, but not understood by the parser
+                let (tag_block, sentence) = nmea::split_tag_block(line);
+                let received = tag_block.and_then(nmea::tag_block_time).unwrap_or(received);
+                match self.nmea_parser.parse_sentence(sentence) {
                     Ok(parsed_message) => {
                         if parsed_message == ParsedMessage::Incomplete {
                             fragments.push(line.to_string());
                             continue;
                         }
                         log::debug!("Parsed message: {:?}", parsed_message);
+                        if let ParsedMessage::Rmc(data) = &parsed_message {
+                            self.broadcast_class_b(data)?;
+                        }
                         let now = SystemTime::now();
 
                         if let (Some(own_vessel), lat, long) = match &parsed_message {
@@ -322,25 +473,39 @@ impl Dispatcher {
                                         self.broadcast_ais(
                                             &parsed_message,
                                             fragments.join("").as_bytes(),
+                                            received,
                                         )?;
                                     }
                                     if own_vessel {
+                                        let schedule = &mut self.schedule;
+                                        let moving =
+                                            schedule.movement.update(lat, long, Instant::now());
                                         log::trace!(
                                             "Compare last sent location: {:?} interval {:?} anchor {:?}",
                                             now,
-                                            next_location_ts,
-                                            next_location_anchor_ts,
+                                            schedule.next_location_ts,
+                                            schedule.next_location_anchor_ts,
                                         );
-                                        if now >= next_location_anchor_ts
-                                            || (now >= next_location_ts
-                                                && is_moving(lat, long, prev_lat, prev_long))
+                                        if now >= schedule.next_location_anchor_ts
+                                            || (now >= schedule.next_location_ts && moving)
                                         {
-                                            prev_lat = lat;
-                                            prev_long = long;
                                             self.last_sent_location = now;
-                                            self.location_tx.send(parsed_message).unwrap();
-                                            next_location_ts = self.next_location_system_time(&now);
-                                            next_location_anchor_ts =
+                                            let timestamp = fix_time(&parsed_message, received);
+                                            let hdop = match self.provider.protocol {
+                                                Protocol::Gpsd => self.gpsd.hdop(),
+                                                _ => None,
+                                            };
+                                            self.location_tx
+                                                .send(LocationUpdate {
+                                                    message: parsed_message,
+                                                    timestamp,
+                                                    mode: nmea::rmc_mode(sentence).unwrap_or('A'),
+                                                    hdop,
+                                                })
+                                                .unwrap();
+                                            self.schedule.next_location_ts =
+                                                self.next_location_system_time(&now);
+                                            self.schedule.next_location_anchor_ts =
                                                 self.next_location_anchor_system_time(&now);
                                         }
                                     }
@@ -357,10 +522,82 @@ impl Dispatcher {
         }
     }
 
-    fn broadcast_ais(&mut self, message: &ParsedMessage, nmea_message: &[u8]) -> io::Result<()> {
+    fn broadcast_ais(
+        &mut self,
+        message: &ParsedMessage,
+        nmea_message: &[u8],
+        received: DateTime<Utc>,
+    ) -> io::Result<()> {
         log::debug!("Broadcasting message: {:?} / {:?}", message, nmea_message);
         for (key, address) in self.ais.iter_mut() {
-            send_message(&nmea_message, key, address)?;
+            match address.protocol {
+                Protocol::MQTT => mqtt::send_ais_mqtt(message, key, address)?,
+                Protocol::SignalKWs | Protocol::SignalKTcp => {
+                    signalk::send_ais_signalk(message, key, address)?
+                }
+                _ => {
+                    let nmea_message = format_ais(nmea_message, address, received);
+                    match self.spools.get_mut(key) {
+                        Some(spool) if !spool.may_send() => spool.store(key, &nmea_message),
+                        Some(spool) => {
+                            let mut result = Ok(());
+                            if !spool.is_empty() {
+                                result = spool.replay(key, |m| send_message(m, key, address));
+                            }
+                            if result.is_ok() {
+                                result = send_message(&nmea_message, key, address);
+                            }
+                            spool.sent(key, result.is_ok());
+                            if let Err(e) = result {
+                                log::warn!("{}: Spooling message: {}", key, e);
+                                spool.store(key, &nmea_message);
+                            }
+                        }
+                        None => send_message(&nmea_message, key, address)?,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Without a transponder, our own position is sent to the AIS endpoints as if we had a
+    // class B transponder. Messages are marked as AIVDO as they are about our own vessel.
+    fn broadcast_class_b(&mut self, rmc: &RmcData) -> io::Result<()> {
+        let statics = match &self.class_b {
+            Some(statics) => statics.clone(),
+            None => return Ok(()),
+        };
+        if rmc.status_active == Some(false) || rmc.latitude.is_none() || rmc.longitude.is_none() {
+            return Ok(());
+        }
+        let position = aivdm::Position {
+            mmsi: statics.mmsi,
+            sog: rmc.sog_knots,
+            latitude: rmc.latitude,
+            longitude: rmc.longitude,
+            cog: rmc.bearing,
+            timestamp_seconds: rmc.timestamp.map(|ts| ts.second() as u8),
+            ..Default::default()
+        };
+        for sentences in [
+            aivdm::encode_class_b_position(&position, true),
+            aivdm::encode_class_b_static(&statics, true),
+        ] {
+            // Part A and B of the static data are sent together
+            let mut parsed_message = None;
+            for line in sentences.lines() {
+                match self.nmea_parser.parse_sentence(line) {
+                    Ok(ParsedMessage::Incomplete) => {}
+                    Ok(message) => parsed_message = Some(message),
+                    Err(e) => log::warn!("Cannot parse own class B message {}: {}", line, e),
+                }
+            }
+            if let Some(message) = parsed_message
+                && self.check_last_sent(&message)
+            {
+                self.broadcast_ais(&message, sentences.as_bytes(), Utc::now())?;
+            }
         }
         Ok(())
     }
@@ -421,59 +658,75 @@ impl Dispatcher {
     }
 }
 
-fn is_moving(lat: f64, long: f64, prev_lat: f64, prev_long: f64) -> bool {
-    let lat_diff = (lat - prev_lat).abs();
-    let long_diff = (long - prev_long).abs();
-
-    lat_diff > 0.001 || long_diff > 0.001
+// Apply the per endpoint options that change the NMEA sentences sent to an AIS endpoint.
+fn format_ais(nmea_message: &[u8], address: &NetworkEndpoint, received: DateTime<Utc>) -> Vec<u8> {
+    let mut nmea_message = nmea_message.to_vec();
+    if address.option("vdo", "keep") == "vdm" {
+        nmea_message = nmea::vdo_to_vdm(&nmea_message);
+    }
+    if address.option("tag_block", "false") == "true" {
+        let station = address.options.get("station").map(|s| s.as_str());
+        nmea_message = nmea::add_tag_blocks(&nmea_message, received, station);
+    }
+    nmea_message
 }
 
-fn send_message(
-    nmea_message: &[u8],
-    key: &String,
-    address: &mut NetworkEndpoint,
-) -> io::Result<()> {
-    match address.protocol {
-        Protocol::TCP => {
-            address.tcp_stream.retain(|writer| {
-                if writer.peer_addr().is_err() {
-                    log::warn!("Removing disconnected TCP stream");
-                    false
-                } else {
-                    true
-                }
-            });
-
-            if address.tcp_stream.len() == 0 {
-                let stream = std::net::TcpStream::connect(address.addr).map_err(|e| {
-                    std::io::Error::new(
-                        std::io::ErrorKind::ConnectionRefused,
-                        format!("{} ({}): {}", key, address.addr, e),
-                    )
-                })?;
+// The [class_b] section describes our own vessel, see config.ini.demo.
+fn class_b_config(class_b: &HashMap<String, String>, mmsi: u32) -> aivdm::Static {
+    fn number<T: std::str::FromStr>(class_b: &HashMap<String, String>, key: &str) -> Option<T>
+    where
+        T::Err: std::fmt::Display,
+    {
+        class_b.get(key).map(|v| match v.parse::<T>() {
+            Ok(v) => v,
+            Err(e) => {
+                log::error!("Invalid {} in [class_b] in config.ini: {}", key, e);
+                exit(1);
+            }
+        })
+    }
 
-                // Set the stream to use keepalive
-                let sock_ref = socket2::SockRef::from(&stream);
-                let mut ka = socket2::TcpKeepalive::new();
-                ka = ka.with_time(Duration::from_secs(30));
-                ka = ka.with_interval(Duration::from_secs(30));
-                sock_ref.set_tcp_keepalive(&ka)?;
+    if mmsi == 0 {
+        log::error!("Set the MMSI in config.ini to report our position as class B");
+        exit(1);
+    }
+    aivdm::Static {
+        mmsi,
+        name: class_b.get("name").cloned(),
+        call_sign: class_b.get("call_sign").cloned(),
+        ship_type: number(class_b, "ship_type"),
+        to_bow: number(class_b, "to_bow"),
+        to_stern: number(class_b, "to_stern"),
+        to_port: number(class_b, "to_port"),
+        to_starboard: number(class_b, "to_starboard"),
+        ..Default::default()
+    }
+}
 
-                log::info!("{}: Connected to {}", key, address);
-                let writer = BufReaderDirectWriter::new(stream);
-                address.tcp_stream.push(writer);
-            }
-            if let Some(tcp_stream) = address.tcp_stream.get_mut(0) {
-                send_message_tcp(tcp_stream, nmea_message).map_err(|e| {
-                    address.tcp_stream.clear();
-                    std::io::Error::new(
-                        std::io::ErrorKind::ConnectionRefused,
-                        format!("send_message tcp {} ({}): {}", key, address.addr, e),
-                    )
-                })?;
-                log::debug!("{}: Sent message to {}", key, address);
+// The time of a position fix. RMC has the full time. An AIS position report only has the
+// second of the minute, which is combined with the time the message was received.
+fn fix_time(message: &ParsedMessage, received: DateTime<Utc>) -> DateTime<Utc> {
+    match message {
+        ParsedMessage::Rmc(data) => data.timestamp.unwrap_or(received),
+        ParsedMessage::VesselDynamicData(data) if data.timestamp_seconds < 60 => {
+            let fix = received
+                .with_second(data.timestamp_seconds as u32)
+                .and_then(|ts| ts.with_nanosecond(0))
+                .unwrap_or(received);
+            match fix > received {
+                true => fix - chrono::Duration::minutes(1), // Fix was in the previous minute
+                false => fix,
             }
         }
+        _ => received,
+    }
+}
+
+fn send_message(nmea_message: &[u8], key: &str, address: &mut NetworkEndpoint) -> io::Result<()> {
+    match address.protocol {
+        Protocol::TCP => {
+            send_tcp(nmea_message, key, address)?;
+        }
         Protocol::UDP => {
             if address.udp_socket.is_none() {
                 let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| {
@@ -495,7 +748,73 @@ fn send_message(
                 })?;
             }
         }
-        Protocol::TCPListen | Protocol::UDPListen => {}
+        Protocol::HTTP | Protocol::HTTPS => {
+            http::send_message_http(nmea_message, key, address)?;
+        }
+        Protocol::APRS => {
+            aprs::send_message_aprs(nmea_message, key, address)?;
+        }
+        Protocol::MQTT => {
+            mqtt::send_message_mqtt(nmea_message, key, address)?;
+        }
+        Protocol::SignalKWs | Protocol::SignalKTcp => {
+            signalk::send_message_signalk(nmea_message, key, address)?;
+        }
+        Protocol::TCPListen | Protocol::UDPListen | Protocol::Gpsd | Protocol::N2kJson => {}
+    }
+    Ok(())
+}
+
+// Send a message over the single outgoing TCP stream of `address`, connecting first if needed.
+// Drop disconnected streams and connect when there is no stream left, with TCP keepalive
+// to detect a dead peer. Returns true when a new connection was made.
+fn connect_tcp(key: &str, address: &mut NetworkEndpoint) -> io::Result<bool> {
+    // Connecting to a host that is down should not hold up the other endpoints for long
+    const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    address.tcp_stream.retain(|writer| {
+        if writer.peer_addr().is_err() {
+            log::warn!("Removing disconnected TCP stream");
+            false
+        } else {
+            true
+        }
+    });
+
+    if !address.tcp_stream.is_empty() {
+        return Ok(false);
+    }
+    let stream =
+        std::net::TcpStream::connect_timeout(&address.addr, TCP_CONNECT_TIMEOUT).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::ConnectionRefused,
+                format!("{} ({}): {}", key, address.addr, e),
+            )
+        })?;
+
+    // Set the stream to use keepalive
+    let sock_ref = socket2::SockRef::from(&stream);
+    let mut ka = socket2::TcpKeepalive::new();
+    ka = ka.with_time(Duration::from_secs(30));
+    ka = ka.with_interval(Duration::from_secs(30));
+    sock_ref.set_tcp_keepalive(&ka)?;
+
+    log::info!("{}: Connected to {}", key, address);
+    address.tcp_stream.push(BufReaderDirectWriter::new(stream));
+    Ok(true)
+}
+
+fn send_tcp(nmea_message: &[u8], key: &str, address: &mut NetworkEndpoint) -> io::Result<()> {
+    connect_tcp(key, address)?;
+    if let Some(tcp_stream) = address.tcp_stream.get_mut(0) {
+        send_message_tcp(tcp_stream, nmea_message).map_err(|e| {
+            address.tcp_stream.clear();
+            std::io::Error::new(
+                std::io::ErrorKind::ConnectionRefused,
+                format!("send_message tcp {} ({}): {}", key, address.addr, e),
+            )
+        })?;
+        log::debug!("{}: Sent message to {}", key, address);
     }
     Ok(())
 }
@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::track;
+
+// The anchor position is the average of the first fixes after stopping, so that it is not
+// thrown off by a single bad fix. Only fixes within half the movement distance of the place
+// where the vessel stopped count, so that a slow departure cannot drag the anchor along.
+const MAX_AVERAGE_FIXES: f64 = 100.0;
+
+/// Settings of the movement detection, from the [general] section.
+#[derive(Debug, Clone)]
+pub struct MovementConfig {
+    pub distance: f64, // Metres
+    pub stop_time: Duration,
+}
+
+impl MovementConfig {
+    pub fn from_config(general: &HashMap<String, String>) -> Result<Self, String> {
+        let distance = match general.get("movement_distance").map(|v| v.parse::<f64>()) {
+            None => 100.0,
+            Some(Ok(distance)) if distance > 0.0 => distance,
+            Some(Ok(distance)) => {
+                return Err(format!(
+                    "Invalid movement_distance: {} is not positive",
+                    distance
+                ));
+            }
+            Some(Err(e)) => return Err(format!("Invalid movement_distance: {}", e)),
+        };
+        let stop_time = match general.get("movement_stop_time").map(|v| v.parse::<u64>()) {
+            None => 600,
+            Some(Ok(stop_time)) => stop_time,
+            Some(Err(e)) => return Err(format!("Invalid movement_stop_time: {}", e)),
+        };
+        Ok(MovementConfig {
+            distance,
+            stop_time: Duration::from_secs(stop_time),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    // Where the vessel stopped, the average position of the fixes near there since, and the
+    // number of fixes in that average.
+    Stationary {
+        stop_latitude: f64,
+        stop_longitude: f64,
+        latitude: f64,
+        longitude: f64,
+        fixes: f64,
+    },
+    // Where and when the vessel was last more than `distance` away from its earlier fixes.
+    Moving {
+        latitude: f64,
+        longitude: f64,
+        since: Instant,
+    },
+}
+
+/// Decides whether the vessel is moving, with hysteresis: a stationary vessel starts moving
+/// once it is more than `distance` metres from its anchor position, which is quick even for
+/// a slow departure but ignores a vessel swinging around its anchor. A moving vessel stops
+/// once it has stayed within `distance` metres of one place for `stop_time`.
+pub struct Movement {
+    config: MovementConfig,
+    state: Option<State>,
+}
+
+impl Movement {
+    pub fn new(config: MovementConfig) -> Self {
+        Movement {
+            config,
+            state: None,
+        }
+    }
+
+    pub fn update(&mut self, latitude: f64, longitude: f64, now: Instant) -> bool {
+        let state = match self.state {
+            None => Self::stopped(latitude, longitude),
+            Some(
+                state @ State::Stationary {
+                    stop_latitude,
+                    stop_longitude,
+                    latitude: anchor_latitude,
+                    longitude: anchor_longitude,
+                    fixes,
+                },
+            ) => {
+                let distance =
+                    track::distance(anchor_latitude, anchor_longitude, latitude, longitude);
+                let from_stop = track::distance(stop_latitude, stop_longitude, latitude, longitude);
+                if distance > self.config.distance {
+                    log::info!("Moving, {:.0} m from the anchor position", distance);
+                    State::Moving {
+                        latitude,
+                        longitude,
+                        since: now,
+                    }
+                } else if fixes < MAX_AVERAGE_FIXES && from_stop <= self.config.distance / 2.0 {
+                    let fixes = fixes + 1.0;
+                    State::Stationary {
+                        stop_latitude,
+                        stop_longitude,
+                        latitude: anchor_latitude + (latitude - anchor_latitude) / fixes,
+                        longitude: anchor_longitude + (longitude - anchor_longitude) / fixes,
+                        fixes,
+                    }
+                } else {
+                    state
+                }
+            }
+            Some(State::Moving {
+                latitude: stop_latitude,
+                longitude: stop_longitude,
+                since,
+            }) => {
+                let distance = track::distance(stop_latitude, stop_longitude, latitude, longitude);
+                if distance > self.config.distance {
+                    State::Moving {
+                        latitude,
+                        longitude,
+                        since: now,
+                    }
+                } else if now.duration_since(since) >= self.config.stop_time {
+                    log::info!(
+                        "Stationary, within {:.0} m for {} s",
+                        self.config.distance,
+                        self.config.stop_time.as_secs()
+                    );
+                    Self::stopped(stop_latitude, stop_longitude)
+                } else {
+                    State::Moving {
+                        latitude: stop_latitude,
+                        longitude: stop_longitude,
+                        since,
+                    }
+                }
+            }
+        };
+        self.state = Some(state);
+        matches!(state, State::Moving { .. })
+    }
+
+    fn stopped(latitude: f64, longitude: f64) -> State {
+        State::Stationary {
+            stop_latitude: latitude,
+            stop_longitude: longitude,
+            latitude,
+            longitude,
+            fixes: 1.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::track::{METRES_PER_DEGREE, METRES_PER_SECOND_PER_KNOT};
+    use std::time::Duration;
+
+    const LATITUDE: f64 = 53.0;
+    const LONGITUDE: f64 = 5.0;
+
+    fn movement() -> Movement {
+        Movement::new(MovementConfig {
+            distance: 100.0,
+            stop_time: Duration::from_secs(600),
+        })
+    }
+
+    // A position `north` and `east` metres from the start.
+    fn offset(north: f64, east: f64) -> (f64, f64) {
+        (
+            LATITUDE + north / METRES_PER_DEGREE,
+            LONGITUDE + east / (METRES_PER_DEGREE * LATITUDE.to_radians().cos()),
+        )
+    }
+
+    // Feed one fix per second for `seconds`, from `north` metres north at `knots` knots
+    // heading north. Returns the second at which the vessel was first moving.
+    fn sail(
+        movement: &mut Movement,
+        start: Instant,
+        seconds: u64,
+        north: f64,
+        knots: f64,
+    ) -> Option<u64> {
+        (0..seconds).find(|&t| {
+            let (latitude, longitude) =
+                offset(north + knots * METRES_PER_SECOND_PER_KNOT * t as f64, 0.0);
+            movement.update(latitude, longitude, start + Duration::from_secs(t))
+        })
+    }
+
+    #[test]
+    fn test_slow_departure() {
+        for knots in [1.0, 1.5, 2.0, 3.0] {
+            let mut movement = movement();
+            let start = Instant::now();
+            assert_eq!(sail(&mut movement, start, 600, 0.0, 0.0), None);
+            let t = sail(
+                &mut movement,
+                start + Duration::from_secs(600),
+                7200,
+                0.0,
+                knots,
+            )
+            .expect("departure not detected");
+            // The anchor moves at most half the distance, so the vessel is at most 150 m out
+            let distance = knots * METRES_PER_SECOND_PER_KNOT * t as f64;
+            assert!(
+                distance <= 150.0,
+                "{} kn detected at {:.0} m",
+                knots,
+                distance
+            );
+        }
+    }
+
+    #[test]
+    fn test_jitter_at_anchor() {
+        let mut movement = movement();
+        let start = Instant::now();
+        // Swinging around the anchor on a 40 m circle, with 15 m of GPS noise
+        for t in 0..7200 {
+            let angle = (t as f64 / 300.0).sin() * std::f64::consts::PI;
+            let noise = ((t * 7919) % 31) as f64 - 15.0;
+            let (latitude, longitude) =
+                offset(40.0 * angle.cos() + noise, 40.0 * angle.sin() - noise);
+            assert!(
+                !movement.update(latitude, longitude, start + Duration::from_secs(t)),
+                "moving at {} s",
+                t
+            );
+        }
+    }
+
+    #[test]
+    fn test_anchor_drag() {
+        let mut movement = movement();
+        let start = Instant::now();
+        assert_eq!(sail(&mut movement, start, 600, 0.0, 0.0), None);
+        // Dragging at 0.2 kn is noticed before the vessel is 150 m away
+        let t = sail(
+            &mut movement,
+            start + Duration::from_secs(600),
+            7200,
+            0.0,
+            0.2,
+        )
+        .expect("drag not detected");
+        let north = 0.2 * METRES_PER_SECOND_PER_KNOT * t as f64;
+        assert!(north <= 150.0, "detected at {:.0} m", north);
+        // Once the anchor holds again the vessel is stationary after `stop_time`
+        let start = start + Duration::from_secs(600 + t);
+        let (latitude, longitude) = offset(north, 0.0);
+        assert!(movement.update(latitude, longitude, start + Duration::from_secs(599)));
+        assert!(!movement.update(latitude, longitude, start + Duration::from_secs(601)));
+        assert_eq!(
+            sail(
+                &mut movement,
+                start + Duration::from_secs(602),
+                3600,
+                north,
+                0.0
+            ),
+            None
+        );
+    }
+}
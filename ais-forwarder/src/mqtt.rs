@@ -0,0 +1,396 @@
+use nmea_parser::ParsedMessage;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::Duration;
+
+use common::NetworkEndpoint;
+use common::buffer::BufReaderDirectWriter;
+use common::send_message_tcp;
+
+use crate::report::{self, LocationReport};
+
+// Minimal MQTT 3.1.1 publisher, all messages are sent with QoS 1 and we wait for the
+// PUBACK so that the caller knows whether the broker has the message.
+//
+// mqtt://broker:1883?topic=ais&username=user&password=secret&client_id=boat
+//
+// Vessels are published as JSON to `<topic>/<mmsi>/position` (retained) and
+// `<topic>/<mmsi>/static`. Our own location reports go to `<topic>/<id>/position`.
+
+const MQTT_ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+
+static PACKET_ID: AtomicU16 = AtomicU16::new(1);
+
+pub fn send_message_mqtt(
+    nmea_message: &[u8],
+    key: &str,
+    address: &mut NetworkEndpoint,
+) -> io::Result<()> {
+    let report = LocationReport::from_message(nmea_message, key)?;
+    // The topic keeps the escaped id, so that it cannot contain `/`, `+` or `#`
+    let topic = format!("{}/{}/position", address.option("topic", "ais"), report.id);
+    let payload = position_json(&report);
+    publish(key, address, &topic, payload.to_string().as_bytes(), true)
+}
+
+fn position_json(report: &LocationReport) -> serde_json::Value {
+    serde_json::json!({
+        "id": report::unescape_id(&report.id),
+        "time": report.timestamp.map(|ts| ts.to_rfc3339()),
+        "lat": report.latitude,
+        "lon": report.longitude,
+        "sog": report.sog,
+        "cog": report.cog,
+    })
+}
+
+pub fn send_ais_mqtt(
+    message: &ParsedMessage,
+    key: &str,
+    address: &mut NetworkEndpoint,
+) -> io::Result<()> {
+    let now = chrono::Utc::now().to_rfc3339();
+    let (mmsi, kind, payload, retain) = match message {
+        ParsedMessage::VesselDynamicData(data) => (
+            data.mmsi,
+            "position",
+            serde_json::json!({
+                "mmsi": data.mmsi,
+                "time": now,
+                "lat": data.latitude,
+                "lon": data.longitude,
+                "sog": data.sog_knots,
+                "cog": data.cog,
+                "heading": data.heading_true,
+                "rot": data.rot,
+                "nav_status": data.nav_status as u8,
+                "own_vessel": data.own_vessel,
+            }),
+            true,
+        ),
+        ParsedMessage::VesselStaticData(data) => (
+            data.mmsi,
+            "static",
+            serde_json::json!({
+                "mmsi": data.mmsi,
+                "time": now,
+                "name": data.name,
+                "call_sign": data.call_sign,
+                "imo": data.imo_number,
+                "ship_type": data.ship_type as u8,
+                "to_bow": data.dimension_to_bow,
+                "to_stern": data.dimension_to_stern,
+                "to_port": data.dimension_to_port,
+                "to_starboard": data.dimension_to_starboard,
+                "destination": data.destination,
+                "own_vessel": data.own_vessel,
+            }),
+            false,
+        ),
+        _ => return Ok(()),
+    };
+    let topic = format!("{}/{}/{}", address.option("topic", "ais"), mmsi, kind);
+    publish(key, address, &topic, payload.to_string().as_bytes(), retain)
+}
+
+fn publish(
+    key: &str,
+    address: &mut NetworkEndpoint,
+    topic: &str,
+    payload: &[u8],
+    retain: bool,
+) -> io::Result<()> {
+    if crate::connect_tcp(key, address)?
+        && let Err(e) = connect(key, address)
+    {
+        address.tcp_stream.clear();
+        return Err(e);
+    }
+
+    let packet_id = next_packet_id();
+    let mut body = Vec::with_capacity(topic.len() + payload.len() + 4);
+    push_string(&mut body, topic);
+    body.extend_from_slice(&packet_id.to_be_bytes());
+    body.extend_from_slice(payload);
+    let header = PUBLISH | 0x02 | if retain { 0x01 } else { 0x00 }; // QoS 1
+
+    if let Some(tcp_stream) = address.tcp_stream.get_mut(0) {
+        let result = send_message_tcp(tcp_stream, &packet(header, &body))
+            .and_then(|_| wait_for_puback(tcp_stream, packet_id));
+        if let Err(e) = result {
+            address.tcp_stream.clear();
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("send_message mqtt {} ({}): {}", key, address.addr, e),
+            ));
+        }
+        log::debug!("{}: Published {} to {}", key, topic, address);
+    }
+    Ok(())
+}
+
+// Send CONNECT on a new connection and wait for the CONNACK. The keep alive interval in the
+// CONNECT packet is disabled, as we may not send anything for a long time; the TCP keepalive
+// of the connection detects a dead broker instead.
+fn connect(key: &str, address: &mut NetworkEndpoint) -> io::Result<()> {
+    let Some(stream) = address.tcp_stream.get_mut(0) else {
+        return Ok(());
+    };
+    stream.set_read_timeout(Some(MQTT_ACK_TIMEOUT))?;
+
+    let client_id = client_id(key, &address.options);
+    let username = address.options.get("username");
+    let password = address.options.get("password");
+
+    let mut flags = 0x02; // Clean session
+    if username.is_some() {
+        flags |= 0x80;
+    }
+    if password.is_some() {
+        flags |= 0x40;
+    }
+    let mut body = Vec::new();
+    push_string(&mut body, "MQTT");
+    body.push(4); // Protocol level 3.1.1
+    body.push(flags);
+    body.extend_from_slice(&0u16.to_be_bytes()); // Keep alive
+    push_string(&mut body, &client_id);
+    if let Some(username) = username {
+        push_string(&mut body, username);
+    }
+    if let Some(password) = password {
+        push_string(&mut body, password);
+    }
+    send_message_tcp(stream, &packet(CONNECT, &body))?;
+
+    let (header, body) = read_packet(stream)?;
+    if header & 0xf0 != CONNACK || body.len() != 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} ({}): expected CONNACK", key, address.addr),
+        ));
+    }
+    if body[1] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} ({}): connection refused with return code {}",
+                key, address.addr, body[1]
+            ),
+        ));
+    }
+    log::info!("{}: Connected to {} as {}", key, address, client_id);
+    Ok(())
+}
+
+// The broker drops a client when another one connects with the same id. The same endpoint
+// can be configured in [ais] and [location], so the default id includes the thread name.
+fn client_id(key: &str, options: &HashMap<String, String>) -> String {
+    match options.get("client_id") {
+        Some(client_id) => client_id.clone(),
+        None => format!(
+            "ais-forwarder-{}-{}-{}",
+            std::thread::current().name().unwrap_or("thread"),
+            key,
+            std::process::id()
+        ),
+    }
+}
+
+fn wait_for_puback(
+    stream: &mut BufReaderDirectWriter<std::net::TcpStream>,
+    packet_id: u16,
+) -> io::Result<()> {
+    loop {
+        let (header, body) = read_packet(stream)?;
+        if header & 0xf0 == PUBACK && body.len() == 2 && body[..2] == packet_id.to_be_bytes() {
+            return Ok(());
+        }
+        log::trace!("Ignoring MQTT packet type {:#04x}", header);
+    }
+}
+
+fn read_packet(
+    stream: &mut BufReaderDirectWriter<std::net::TcpStream>,
+) -> io::Result<(u8, Vec<u8>)> {
+    let mut byte = [0u8; 1];
+    stream.read_exact(&mut byte)?;
+    let header = byte[0];
+
+    let mut length = 0usize;
+    for shift in (0..28).step_by(7) {
+        stream.read_exact(&mut byte)?;
+        length |= ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+    let mut body = vec![0u8; length];
+    stream.read_exact(&mut body)?;
+    Ok((header, body))
+}
+
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(body.len() + 5);
+    packet.push(header);
+    let mut length = body.len();
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn push_string(buffer: &mut Vec<u8>, s: &str) {
+    buffer.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(s.as_bytes());
+}
+
+fn next_packet_id() -> u16 {
+    loop {
+        let id = PACKET_ID.fetch_add(1, Ordering::Relaxed);
+        if id != 0 {
+            return id;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    // Read the string at the start of a packet body and return it with the rest of the body.
+    fn split_string(body: &[u8]) -> (String, &[u8]) {
+        let length = u16::from_be_bytes([body[0], body[1]]) as usize;
+        let string = String::from_utf8(body[2..2 + length].to_vec()).unwrap();
+        (string, &body[2 + length..])
+    }
+
+    type Packet = (u8, Vec<u8>);
+
+    // A broker that accepts one connection, answers CONNECT with `return_code` and then
+    // acknowledges one PUBLISH. Returns its address and the packets it received.
+    fn broker(return_code: u8) -> (String, std::thread::JoinHandle<Vec<Packet>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!(
+            "mqtt://{}?topic=test&client_id=boat",
+            listener.local_addr().unwrap()
+        );
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream = BufReaderDirectWriter::new(stream);
+            let mut received = vec![read_packet(&mut stream).unwrap()];
+            stream.write_all(&[CONNACK, 2, 0, return_code]).unwrap();
+            if return_code == 0 {
+                let (header, body) = read_packet(&mut stream).unwrap();
+                let (_, rest) = split_string(&body);
+                stream.write_all(&packet(PUBACK, &rest[..2])).unwrap();
+                received.push((header, body));
+            }
+            received
+        });
+        (address, handle)
+    }
+
+    #[test]
+    fn test_packet_length() {
+        assert_eq!(packet(PUBACK, &[0, 1]), vec![PUBACK, 2, 0, 1]);
+        let body = vec![0x55; 321];
+        let encoded = packet(PUBLISH, &body);
+        // 321 = 65 + 2 * 128
+        assert_eq!(encoded[..3], [PUBLISH, 0xc1, 0x02]);
+        assert_eq!(encoded.len(), 3 + body.len());
+    }
+
+    #[test]
+    fn test_read_packet() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let body = vec![0xaa; 20000];
+        client.write_all(&packet(PUBLISH | 0x02, &body)).unwrap();
+        let mut server = BufReaderDirectWriter::new(server);
+        assert_eq!(read_packet(&mut server).unwrap(), (PUBLISH | 0x02, body));
+    }
+
+    #[test]
+    fn test_push_string() {
+        let mut buffer = Vec::new();
+        push_string(&mut buffer, "MQTT");
+        assert_eq!(buffer, b"\x00\x04MQTT");
+    }
+
+    #[test]
+    fn test_publish() {
+        let (address, broker) = broker(0);
+        let mut address = address.parse::<NetworkEndpoint>().unwrap();
+        publish("test", &mut address, "test/244123456/position", b"{}", true).unwrap();
+        assert_eq!(address.tcp_stream.len(), 1);
+
+        let received = broker.join().unwrap();
+        let (header, body) = &received[0];
+        assert_eq!(*header, CONNECT);
+        let (protocol, rest) = split_string(body);
+        assert_eq!(protocol, "MQTT");
+        assert_eq!(rest[..4], [4, 0x02, 0, 0]); // Level, clean session, no keep alive
+        assert_eq!(split_string(&rest[4..]).0, "boat");
+
+        let (header, body) = &received[1];
+        assert_eq!(*header, PUBLISH | 0x02 | 0x01); // QoS 1, retained
+        let (topic, rest) = split_string(body);
+        assert_eq!(topic, "test/244123456/position");
+        assert_eq!(&rest[2..], b"{}");
+    }
+
+    #[test]
+    fn test_connect_refused() {
+        let (address, broker) = broker(5);
+        let mut address = address.parse::<NetworkEndpoint>().unwrap();
+        let e = publish("test", &mut address, "test", b"{}", false).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert!(address.tcp_stream.is_empty());
+        broker.join().unwrap();
+    }
+
+    #[test]
+    fn test_position_json() {
+        let message =
+            b"Sea%20Breeze$GNRMC,123519.00,A,5301.12345,N,00524.12345,E,5.2,181.0,150525,,,A*00";
+        let report = LocationReport::from_message(message, "test").unwrap();
+        let payload = position_json(&report);
+        assert_eq!(payload["id"], "Sea Breeze");
+        assert_eq!(payload["sog"], 5.2);
+        assert_eq!(payload["time"], "2025-05-15T12:35:19+00:00");
+    }
+
+    #[test]
+    fn test_default_client_id() {
+        let options = HashMap::new();
+        let id = std::thread::Builder::new()
+            .name("location".to_string())
+            .spawn(move || client_id("mqtt", &options))
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(
+            id,
+            format!("ais-forwarder-location-mqtt-{}", std::process::id())
+        );
+    }
+}
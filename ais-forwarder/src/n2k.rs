@@ -0,0 +1,393 @@
+use chrono::{DateTime, NaiveDateTime, Timelike, Utc};
+use serde_json::Value;
+use std::collections::HashMap;
+
+use crate::aivdm;
+use crate::nmea;
+use crate::track::METRES_PER_SECOND_PER_KNOT;
+
+// Reads the JSON stream of canboat n2kd, see https://github.com/canboat/canboat
+//
+// n2kd-json://127.0.0.1:2597
+//
+// Each line is a decoded NMEA 2000 message as printed by the canboat analyzer, for example
+// {"timestamp":"2024-06-01T12:00:00.000Z","prio":2,"src":3,"dst":255,"pgn":129025,
+//  "description":"Position, Rapid Update","fields":{"Latitude":52.1234567,"Longitude":4.1234567}}
+//
+// The analyzer prints angles in degrees and speeds in m/s. AIS class A PGNs are re-encoded as
+// AIVDM types 1 and 5, class B PGNs (129039, 129809, 129810) as types 18 and 24.
+
+const NAV_STATUS: &[(u8, &str)] = &[
+    (0, "Under way using engine"),
+    (1, "At anchor"),
+    (2, "Not under command"),
+    (3, "Restricted manoeuverability"),
+    (4, "Constrained by her draught"),
+    (5, "Moored"),
+    (6, "Aground"),
+    (7, "Engaged in Fishing"),
+    (8, "Under way sailing"),
+    (14, "AIS-SART"),
+];
+
+const SHIP_TYPE: &[(u8, &str)] = &[
+    (0, "unavailable"),
+    (20, "Wing In Ground"),
+    (30, "Fishing"),
+    (31, "Towing"),
+    (32, "Towing exceeds 200m or wider than 25m"),
+    (33, "Engaged in dredging or underwater operations"),
+    (34, "Engaged in diving operations"),
+    (35, "Engaged in military operations"),
+    (36, "Sailing"),
+    (37, "Pleasure"),
+    (40, "High speed craft"),
+    (50, "Pilot vessel"),
+    (51, "SAR"),
+    (52, "Tug"),
+    (53, "Port tender"),
+    (54, "Anti-pollution"),
+    (55, "Law enforcement"),
+    (58, "Medical"),
+    (60, "Passenger ship"),
+    (70, "Cargo ship"),
+    (80, "Tanker"),
+    (90, "Other"),
+];
+
+/// Turns the PGNs in the JSON stream of n2kd into NMEA 0183 sentences. Our own position
+/// (PGN 129025 and 129026) becomes a RMC sentence, AIS PGNs are encoded as AIVDM/AIVDO
+/// messages. Unlike the NMEA 0183 stream of n2kd this keeps heading and rate of turn.
+pub struct N2kInput {
+    sog: Option<f64>,
+    cog: Option<f64>,
+    // Class B static data arrives in two parts, keep what we have seen so far
+    statics: HashMap<u32, aivdm::Static>,
+}
+
+impl N2kInput {
+    pub fn new() -> Self {
+        N2kInput {
+            sog: None,
+            cog: None,
+            statics: HashMap::new(),
+        }
+    }
+
+    pub fn translate(&mut self, line: &str) -> String {
+        let message = match serde_json::from_str::<Value>(line) {
+            Ok(message) => message,
+            Err(e) => {
+                log::warn!("Invalid n2kd message '{}': {}", line.trim_end(), e);
+                return String::new();
+            }
+        };
+        let fields = match message.get("fields") {
+            Some(fields) => fields,
+            None => return String::new(),
+        };
+        let timestamp = message
+            .get("timestamp")
+            .and_then(Value::as_str)
+            .and_then(parse_timestamp);
+
+        match message.get("pgn").and_then(Value::as_u64) {
+            Some(129025) => {
+                let latitude = number(fields, "Latitude");
+                let longitude = number(fields, "Longitude");
+                match (latitude, longitude) {
+                    (Some(latitude), Some(longitude)) => nmea::rmc(
+                        timestamp.unwrap_or_else(Utc::now),
+                        latitude,
+                        longitude,
+                        self.sog,
+                        self.cog,
+                        'A',
+                    ),
+                    _ => String::new(),
+                }
+            }
+            Some(129026) => {
+                if fields.get("COG Reference").and_then(Value::as_str) == Some("True") {
+                    self.cog = number(fields, "COG");
+                }
+                self.sog = number(fields, "SOG").map(|sog| sog / METRES_PER_SECOND_PER_KNOT);
+                String::new()
+            }
+            Some(pgn @ (129038 | 129039)) => {
+                let position = aivdm::Position {
+                    mmsi: mmsi(fields),
+                    nav_status: match pgn {
+                        129038 => lookup(fields, "Nav Status", NAV_STATUS),
+                        _ => None,
+                    },
+                    // deg/s
+                    rot: number(fields, "Rate of Turn").map(|rot| rot * 60.0),
+                    sog: number(fields, "SOG").map(|sog| sog / METRES_PER_SECOND_PER_KNOT),
+                    latitude: number(fields, "Latitude"),
+                    longitude: number(fields, "Longitude"),
+                    cog: number(fields, "COG"),
+                    heading: number(fields, "Heading"),
+                    timestamp_seconds: number(fields, "Time Stamp")
+                        .map(|s| s as u8)
+                        .or(timestamp.map(|ts| ts.second() as u8)),
+                };
+                match pgn {
+                    129038 => aivdm::encode_position(&position, own_vessel(fields)),
+                    _ => aivdm::encode_class_b_position(&position, own_vessel(fields)),
+                }
+            }
+            Some(129794) => {
+                let length = number(fields, "Length");
+                let beam = number(fields, "Beam");
+                let from_bow = number(fields, "Position reference from Bow");
+                let from_starboard = number(fields, "Position reference from Starboard");
+                let data = aivdm::Static {
+                    mmsi: mmsi(fields),
+                    imo: number(fields, "IMO number").map(|imo| imo as u32),
+                    call_sign: text(fields, "Callsign"),
+                    name: text(fields, "Name"),
+                    ship_type: lookup(fields, "Type of ship", SHIP_TYPE),
+                    to_bow: from_bow.map(|d| d.round() as u16),
+                    to_stern: remainder(length, from_bow),
+                    to_port: remainder(beam, from_starboard),
+                    to_starboard: from_starboard.map(|d| d.round() as u16),
+                    destination: text(fields, "Destination"),
+                };
+                aivdm::encode_static(&data, own_vessel(fields))
+            }
+            Some(129809) => {
+                let mmsi = mmsi(fields);
+                let data = self.statics.entry(mmsi).or_default();
+                data.mmsi = mmsi;
+                data.name = text(fields, "Name");
+                aivdm::encode_class_b_static(data, own_vessel(fields))
+            }
+            Some(129810) => {
+                let mmsi = mmsi(fields);
+                let length = number(fields, "Length");
+                let beam = number(fields, "Beam");
+                let from_bow = number(fields, "Position reference from Bow");
+                let from_starboard = number(fields, "Position reference from Starboard");
+                let data = self.statics.entry(mmsi).or_default();
+                data.mmsi = mmsi;
+                data.call_sign = text(fields, "Callsign");
+                data.ship_type = lookup(fields, "Type of ship", SHIP_TYPE);
+                data.to_bow = from_bow.map(|d| d.round() as u16);
+                data.to_stern = remainder(length, from_bow);
+                data.to_port = remainder(beam, from_starboard);
+                data.to_starboard = from_starboard.map(|d| d.round() as u16);
+                aivdm::encode_class_b_static(data, own_vessel(fields))
+            }
+            _ => String::new(),
+        }
+    }
+}
+
+// The analyzer has used both `2024-06-01-12:00:00.000` and RFC 3339 timestamps.
+fn parse_timestamp(timestamp: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(timestamp)
+        .map(|ts| ts.with_timezone(&Utc))
+        .or_else(|_| {
+            NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d-%H:%M:%S%.f").map(|ts| ts.and_utc())
+        })
+        .ok()
+}
+
+// Numbers may be printed as strings, for example the AIS time stamp.
+fn number(fields: &Value, name: &str) -> Option<f64> {
+    match fields.get(name)? {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+fn text(fields: &Value, name: &str) -> Option<String> {
+    fields
+        .get(name)
+        .and_then(Value::as_str)
+        .map(|s| s.trim_end_matches('@').trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+// Enumerations are printed by name, or by value when the name is unknown.
+fn lookup(fields: &Value, name: &str, table: &[(u8, &str)]) -> Option<u8> {
+    match fields.get(name)? {
+        Value::Number(n) => n.as_u64().map(|n| n as u8),
+        Value::String(s) => table
+            .iter()
+            .find(|(_, description)| description == s)
+            .map(|(value, _)| *value),
+        _ => None,
+    }
+}
+
+fn mmsi(fields: &Value) -> u32 {
+    number(fields, "User ID").unwrap_or(0.0) as u32
+}
+
+// Messages that our own transponder transmitted, or would have transmitted.
+fn own_vessel(fields: &Value) -> bool {
+    fields
+        .get("AIS Transceiver information")
+        .and_then(Value::as_str)
+        .is_some_and(|info| info.contains("transmission") || info.starts_with("Own information"))
+}
+
+fn remainder(total: Option<f64>, part: Option<f64>) -> Option<u16> {
+    match (total, part) {
+        (Some(total), Some(part)) if total >= part => Some((total - part).round() as u16),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const TIMESTAMP: &str = "2025-05-15T12:35:19.000Z";
+
+    fn pgn(pgn: u32, fields: Value) -> String {
+        json!({"timestamp": TIMESTAMP, "prio": 2, "src": 3, "dst": 255, "pgn": pgn, "fields": fields})
+            .to_string()
+    }
+
+    // The payloads of the AIVDM sentences, without the sequential message id that differs.
+    fn payloads(sentences: &str) -> Vec<String> {
+        sentences
+            .lines()
+            .map(|line| line.split(',').nth(5).unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_own_position() {
+        let mut input = N2kInput::new();
+        let cog_sog = json!({"COG Reference": "True", "COG": 181.3, "SOG": 2.5});
+        assert_eq!(input.translate(&pgn(129026, cog_sog)), "");
+        let position = json!({"Latitude": 53.0187241, "Longitude": -5.4020573});
+        let expected = nmea::rmc(
+            TIMESTAMP.parse().unwrap(),
+            53.0187241,
+            -5.4020573,
+            Some(2.5 / METRES_PER_SECOND_PER_KNOT),
+            Some(181.3),
+            'A',
+        );
+        assert_eq!(input.translate(&pgn(129025, position)), expected);
+    }
+
+    #[test]
+    fn test_class_a_position() {
+        let mut input = N2kInput::new();
+        let fields = json!({
+            "User ID": 244123456,
+            "Nav Status": "Under way sailing",
+            "Rate of Turn": -0.2,
+            "SOG": 2.5,
+            "Latitude": 53.018724,
+            "Longitude": -5.402057,
+            "COG": 181.3,
+            "Heading": 183.0,
+            "Time Stamp": "17",
+            "AIS Transceiver information": "Channel A VDL reception",
+        });
+        let expected = aivdm::Position {
+            mmsi: 244123456,
+            nav_status: Some(8),
+            rot: Some(-0.2 * 60.0),
+            sog: Some(2.5 / METRES_PER_SECOND_PER_KNOT),
+            latitude: Some(53.018724),
+            longitude: Some(-5.402057),
+            cog: Some(181.3),
+            heading: Some(183.0),
+            timestamp_seconds: Some(17),
+        };
+        let sentences = input.translate(&pgn(129038, fields));
+        assert_eq!(sentences, aivdm::encode_position(&expected, false));
+        assert!(sentences.starts_with("!AIVDM,"));
+    }
+
+    #[test]
+    fn test_own_class_b_position() {
+        let mut input = N2kInput::new();
+        let fields = json!({
+            "User ID": 244123456,
+            "Latitude": 53.018724,
+            "Longitude": -5.402057,
+            "AIS Transceiver information": "Own information not broadcast",
+        });
+        let expected = aivdm::Position {
+            mmsi: 244123456,
+            latitude: Some(53.018724),
+            longitude: Some(-5.402057),
+            timestamp_seconds: Some(19),
+            ..Default::default()
+        };
+        let sentences = input.translate(&pgn(129039, fields));
+        assert_eq!(sentences, aivdm::encode_class_b_position(&expected, true));
+        assert!(sentences.starts_with("!AIVDO,"));
+    }
+
+    #[test]
+    fn test_class_a_static() {
+        let mut input = N2kInput::new();
+        let fields = json!({
+            "User ID": 244123456,
+            "IMO number": 9074729,
+            "Callsign": "PD1234@@",
+            "Name": "SEA BREEZE@@@@@@@@@@",
+            "Type of ship": "Sailing",
+            "Length": 12.0,
+            "Beam": 4.0,
+            "Position reference from Starboard": 2.0,
+            "Position reference from Bow": 9.0,
+            "Destination": "DEN HELDER",
+        });
+        let expected = aivdm::Static {
+            mmsi: 244123456,
+            imo: Some(9074729),
+            call_sign: Some("PD1234".to_string()),
+            name: Some("SEA BREEZE".to_string()),
+            ship_type: Some(36),
+            to_bow: Some(9),
+            to_stern: Some(3),
+            to_port: Some(2),
+            to_starboard: Some(2),
+            destination: Some("DEN HELDER".to_string()),
+        };
+        assert_eq!(
+            payloads(&input.translate(&pgn(129794, fields))),
+            payloads(&aivdm::encode_static(&expected, false))
+        );
+    }
+
+    #[test]
+    fn test_class_b_static_parts() {
+        let mut input = N2kInput::new();
+        let part_a = json!({"User ID": 244123456, "Name": "SEA BREEZE"});
+        input.translate(&pgn(129809, part_a));
+        let part_b = json!({"User ID": 244123456, "Callsign": "PD1234", "Type of ship": 36});
+        let expected = aivdm::Static {
+            mmsi: 244123456,
+            name: Some("SEA BREEZE".to_string()),
+            call_sign: Some("PD1234".to_string()),
+            ship_type: Some(36),
+            ..Default::default()
+        };
+        assert_eq!(
+            payloads(&input.translate(&pgn(129810, part_b))),
+            payloads(&aivdm::encode_class_b_static(&expected, false))
+        );
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        let expected = TIMESTAMP.parse::<DateTime<Utc>>().ok();
+        assert_eq!(parse_timestamp(TIMESTAMP), expected);
+        assert_eq!(parse_timestamp("2025-05-15-12:35:19.000"), expected);
+        assert_eq!(parse_timestamp("yesterday"), None);
+    }
+}
@@ -0,0 +1,433 @@
+// Formatting of NMEA 0183 sentences that we generate ourselves.
+
+use chrono::{DateTime, Utc};
+
+/// NMEA checksum: the XOR of all characters between the start delimiter and the `*`.
+pub fn checksum(sentence: &str) -> u8 {
+    sentence.bytes().fold(0, |acc, b| acc ^ b)
+}
+
+/// Add the `$` start delimiter, checksum and CR LF to a sentence.
+pub fn finish(sentence: &str) -> String {
+    format!("${}*{:02X}\r\n", sentence, checksum(sentence))
+}
+
+/// Format a latitude as `ddmm.mmmmm,N` or a longitude as `dddmm.mmmmm,E`.
+pub fn format_lat_long(value: f64, is_lat: bool) -> String {
+    let hemisphere = match (is_lat, value >= 0.0) {
+        (true, true) => "N",
+        (true, false) => "S",
+        (false, true) => "E",
+        (false, false) => "W",
+    };
+    let abs_value = value.abs();
+    let mut degrees = abs_value.trunc();
+    let mut minutes = (abs_value - degrees) * 60.0;
+    if minutes >= 59.999995 {
+        // Would be printed as 60.00000
+        degrees += 1.0;
+        minutes = 0.0;
+    }
+    match is_lat {
+        true => format!("{:02}{:08.5},{}", degrees, minutes, hemisphere),
+        false => format!("{:03}{:08.5},{}", degrees, minutes, hemisphere),
+    }
+}
+
+/// A RMC sentence for a valid fix, `mode` is the NMEA 2.3 mode indicator.
+pub fn rmc(
+    timestamp: DateTime<Utc>,
+    latitude: f64,
+    longitude: f64,
+    sog: Option<f64>,
+    cog: Option<f64>,
+    mode: char,
+) -> String {
+    Fix {
+        timestamp,
+        latitude,
+        longitude,
+        sog,
+        cog,
+        variation: None,
+        heading: None,
+        rot: None,
+        nav_status: None,
+        mode,
+        hdop: None,
+    }
+    .rmc("GP")
+}
+
+/// The NMEA 2.3 mode indicator of a RMC sentence, if it has one.
+pub fn rmc_mode(sentence: &str) -> Option<char> {
+    let sentence = sentence.split('*').next()?;
+    if sentence.get(3..6) != Some("RMC") {
+        return None;
+    }
+    sentence.split(',').nth(12)?.chars().next()
+}
+
+/// A valid position fix, from which the standard NMEA 0183 (version 2.3) sentences are made.
+#[derive(Debug, Clone)]
+pub struct Fix {
+    pub timestamp: DateTime<Utc>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub sog: Option<f64>,       // Knots
+    pub cog: Option<f64>,       // Degrees true
+    pub variation: Option<f64>, // Degrees, positive is east
+    pub heading: Option<f64>,   // Degrees true
+    pub rot: Option<f64>,       // Degrees per minute, positive is to starboard
+    pub nav_status: Option<u8>, // AIS navigational status
+    /// Mode indicator: `A` autonomous, `D` differential, `E` estimated (dead reckoning).
+    pub mode: char,
+    pub hdop: Option<f64>,
+}
+
+impl Fix {
+    /// Recommended minimum data.
+    pub fn rmc(&self, talker: &str) -> String {
+        finish(&format!(
+            "{}RMC,{},A,{},{},{},{},{},{},{}",
+            talker,
+            self.time(),
+            format_lat_long(self.latitude, true),
+            format_lat_long(self.longitude, false),
+            format_option(self.sog),
+            format_option(self.cog),
+            self.timestamp.format("%d%m%y"),
+            format_variation(self.variation),
+            self.mode
+        ))
+    }
+
+    /// Fix data. The HDOP is filled in when the source reports it; we do not know the number
+    /// of satellites or the altitude so those are empty.
+    pub fn gga(&self, talker: &str) -> String {
+        finish(&format!(
+            "{}GGA,{},{},{},{},,{},,M,,M,,",
+            talker,
+            self.time(),
+            format_lat_long(self.latitude, true),
+            format_lat_long(self.longitude, false),
+            self.quality(),
+            format_option(self.hdop)
+        ))
+    }
+
+    /// GGA fix quality: 1 GPS, 2 differential GPS, 6 estimated (dead reckoning).
+    pub fn quality(&self) -> u8 {
+        match self.mode {
+            'D' => 2,
+            'E' => 6,
+            _ => 1,
+        }
+    }
+
+    /// Course and speed over ground.
+    pub fn vtg(&self, talker: &str) -> String {
+        let magnetic = match (self.cog, self.variation) {
+            (Some(cog), Some(variation)) => Some((cog - variation).rem_euclid(360.0)),
+            _ => None,
+        };
+        finish(&format!(
+            "{}VTG,{},T,{},M,{},N,{},K,{}",
+            talker,
+            format_option(self.cog),
+            format_option(magnetic),
+            format_option(self.sog),
+            format_option(self.sog.map(|sog| sog * 1.852)),
+            self.mode
+        ))
+    }
+
+    /// Time and date, in UTC.
+    pub fn zda(&self, talker: &str) -> String {
+        finish(&format!(
+            "{}ZDA,{},{},00,00",
+            talker,
+            self.time(),
+            self.timestamp.format("%d,%m,%Y")
+        ))
+    }
+
+    /// Proprietary sentence with the fields that RMC lacks, as reported by AIS:
+    /// `$PAISF,<heading>,<navigational status>,<rate of turn>`.
+    pub fn extended(&self) -> String {
+        finish(&format!(
+            "PAISF,{},{},{}",
+            format_option(self.heading),
+            self.nav_status.map(|s| s.to_string()).unwrap_or_default(),
+            format_option(self.rot)
+        ))
+    }
+
+    // `hhmmss.ss`
+    fn time(&self) -> String {
+        format!(
+            "{}.{:02}",
+            self.timestamp.format("%H%M%S"),
+            self.timestamp.timestamp_subsec_millis() / 10
+        )
+    }
+}
+
+fn format_option(value: Option<f64>) -> String {
+    value.map(|v| format!("{:.1}", v)).unwrap_or_default()
+}
+
+// `x.x,E`, or two empty fields when unknown
+fn format_variation(variation: Option<f64>) -> String {
+    match variation {
+        Some(variation) if variation < 0.0 => format!("{:.1},W", -variation),
+        Some(variation) => format!("{:.1},E", variation),
+        None => ",".to_string(),
+    }
+}
+
+/// Split a NMEA 4.x tag block, `\c:1718200000,s:station*hh\`, from the sentence that follows.
+pub fn split_tag_block(line: &str) -> (Option<&str>, &str) {
+    if let Some(rest) = line.strip_prefix('\\')
+        && let Some((tag_block, sentence)) = rest.split_once('\\')
+    {
+        return (Some(tag_block), sentence);
+    }
+    (None, line)
+}
+
+/// Whether a station name can go into a tag block, where `,`, `*`, `\\` and `!` are delimiters.
+pub fn is_valid_station(station: &str) -> bool {
+    !station.is_empty() && !station.contains([',', '*', '\\', '!']) && station.is_ascii()
+}
+
+/// A NMEA 4.x tag block with the receive time in unix seconds and optionally the station,
+/// which must be valid according to `is_valid_station`.
+pub fn tag_block(received: DateTime<Utc>, station: Option<&str>) -> String {
+    let mut tags = format!("c:{}", received.timestamp());
+    if let Some(station) = station {
+        tags.push_str(&format!(",s:{}", station));
+    }
+    format!("\\{}*{:02X}\\", tags, checksum(&tags))
+}
+
+/// The receive time from the `c:` tag of a tag block, in unix seconds or milliseconds.
+pub fn tag_block_time(tag_block: &str) -> Option<DateTime<Utc>> {
+    let tags = tag_block.split('*').next()?;
+    let time = tags
+        .split(',')
+        .find_map(|tag| tag.strip_prefix("c:"))?
+        .parse::<i64>()
+        .ok()?;
+    match time > 100_000_000_000 {
+        true => DateTime::from_timestamp_millis(time),
+        false => DateTime::from_timestamp(time, 0),
+    }
+}
+
+/// Prepend a tag block to the sentences that do not have one yet.
+pub fn add_tag_blocks(message: &[u8], received: DateTime<Utc>, station: Option<&str>) -> Vec<u8> {
+    let tag_block = tag_block(received, station);
+    let message = String::from_utf8_lossy(message);
+    let mut result = String::with_capacity(message.len() + tag_block.len());
+    for line in message.split_inclusive('\n') {
+        let (line, end_of_line) = line.split_at(line.trim_end_matches(['\r', '\n']).len());
+        for sentence in split_sentences(line) {
+            if split_tag_block(sentence).0.is_none() {
+                result.push_str(&tag_block);
+            }
+            result.push_str(sentence);
+        }
+        result.push_str(end_of_line);
+    }
+    result.into_bytes()
+}
+
+/// Rewrite `!AIVDO` sentences, reports about our own vessel, into `!AIVDM` sentences as most
+/// AIS aggregators ignore VDO. Other sentences are returned unchanged.
+pub fn vdo_to_vdm(message: &[u8]) -> Vec<u8> {
+    let message = String::from_utf8_lossy(message);
+    let mut result = String::with_capacity(message.len());
+    for line in message.split_inclusive('\n') {
+        let (line, end_of_line) = line.split_at(line.trim_end_matches(['\r', '\n']).len());
+        for part in split_sentences(line) {
+            let (_, sentence) = split_tag_block(part);
+            let tag_block = &part[..part.len() - sentence.len()];
+            match sentence
+                .strip_prefix('!')
+                .and_then(|s| s.split_once('*'))
+                .filter(|(body, _)| body.get(2..5) == Some("VDO"))
+            {
+                Some((body, _)) => {
+                    let body = format!("{}VDM{}", &body[..2], &body[5..]);
+                    result.push_str(tag_block);
+                    result.push_str(&format!("!{}*{:02X}", body, checksum(&body)));
+                }
+                None => result.push_str(part),
+            }
+        }
+        result.push_str(end_of_line);
+    }
+    result.into_bytes()
+}
+
+// Split a line into its sentences, each with its tag block if any. The fragments of a
+// multi-sentence AIS message are forwarded as one line, without line endings in between.
+fn split_sentences(line: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let mut rest = line;
+    while !rest.is_empty() {
+        let (_, sentence) = split_tag_block(rest);
+        let tag_block_len = rest.len() - sentence.len();
+        // The sentence ends after the two checksum characters
+        let end = match sentence.find('*') {
+            Some(i) if sentence.is_char_boundary((i + 3).min(sentence.len())) => {
+                (i + 3).min(sentence.len())
+            }
+            _ => sentence.len(),
+        };
+        let (part, next) = rest.split_at(tag_block_len + end);
+        sentences.push(part);
+        rest = next;
+    }
+    sentences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn fix() -> Fix {
+        Fix {
+            timestamp: Utc.with_ymd_and_hms(2025, 5, 15, 12, 35, 19).unwrap()
+                + chrono::Duration::milliseconds(250),
+            latitude: 53.018724,
+            longitude: -5.402057,
+            sog: Some(5.2),
+            cog: Some(181.0),
+            variation: Some(-1.5),
+            heading: Some(183.0),
+            rot: Some(-2.5),
+            nav_status: Some(0),
+            mode: 'A',
+            hdop: None,
+        }
+    }
+
+    #[test]
+    fn test_rmc() {
+        assert_eq!(
+            fix().rmc("GN"),
+            "$GNRMC,123519.25,A,5301.12344,N,00524.12342,W,5.2,181.0,150525,1.5,W,A*2E\r\n"
+        );
+    }
+
+    #[test]
+    fn test_rmc_without_course() {
+        let fix = Fix {
+            sog: None,
+            cog: None,
+            variation: None,
+            heading: None,
+            rot: None,
+            nav_status: None,
+            ..fix()
+        };
+        assert_eq!(
+            fix.rmc("GN"),
+            "$GNRMC,123519.25,A,5301.12344,N,00524.12342,W,,,150525,,,A*5C\r\n"
+        );
+        assert_eq!(fix.extended(), "$PAISF,,,*61\r\n");
+    }
+
+    #[test]
+    fn test_gga() {
+        assert_eq!(
+            fix().gga("GN"),
+            "$GNGGA,123519.25,5301.12344,N,00524.12342,W,1,,,,M,,M,,*76\r\n"
+        );
+    }
+
+    #[test]
+    fn test_gga_differential() {
+        let fix = Fix {
+            mode: 'D',
+            hdop: Some(0.9),
+            ..fix()
+        };
+        assert_eq!(
+            fix.gga("GN"),
+            "$GNGGA,123519.25,5301.12344,N,00524.12342,W,2,,0.9,,M,,M,,*52\r\n"
+        );
+    }
+
+    #[test]
+    fn test_rmc_mode() {
+        let fix = Fix { mode: 'D', ..fix() };
+        assert_eq!(rmc_mode(&fix.rmc("GN")), Some('D'));
+        assert_eq!(
+            rmc_mode("$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A"),
+            None
+        );
+        assert_eq!(rmc_mode(&fix.gga("GN")), None);
+    }
+
+    #[test]
+    fn test_vtg() {
+        assert_eq!(
+            fix().vtg("GN"),
+            "$GNVTG,181.0,T,182.5,M,5.2,N,9.6,K,A*33\r\n"
+        );
+    }
+
+    #[test]
+    fn test_extended() {
+        assert_eq!(fix().extended(), "$PAISF,183.0,0,-2.5*71\r\n");
+    }
+
+    #[test]
+    fn test_vdo_to_vdm_fragments() {
+        let message = "!AIVDO,2,1,3,A,53`l7@02:N2U0C7;?@1<D6098DE`D0000000000T1832240Ht011C`21C11D,0*52\
+            !AIVDO,2,2,3,A,P0000000000,2*45";
+        assert_eq!(
+            String::from_utf8(vdo_to_vdm(message.as_bytes())).unwrap(),
+            "!AIVDM,2,1,3,A,53`l7@02:N2U0C7;?@1<D6098DE`D0000000000T1832240Ht011C`21C11D,0*50\
+            !AIVDM,2,2,3,A,P0000000000,2*47"
+        );
+    }
+
+    #[test]
+    fn test_add_tag_blocks_fragments() {
+        let received = DateTime::from_timestamp(1718200000, 0).unwrap();
+        let tag_block = tag_block(received, Some("boat"));
+        assert_eq!(tag_block, "\\c:1718200000,s:boat*29\\");
+        let message = "!AIVDO,2,1,3,A,53`l7@02,0*0A!AIVDO,2,2,3,A,P00,2*4B\r\n";
+        assert_eq!(
+            String::from_utf8(add_tag_blocks(message.as_bytes(), received, Some("boat"))).unwrap(),
+            format!(
+                "{}!AIVDO,2,1,3,A,53`l7@02,0*0A{}!AIVDO,2,2,3,A,P00,2*4B\r\n",
+                tag_block, tag_block
+            )
+        );
+        // Sentences that already have a tag block keep it
+        let message = format!("{}!AIVDO,1,1,,A,B00,0*00", tag_block);
+        assert_eq!(
+            add_tag_blocks(message.as_bytes(), received, None),
+            message.as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_is_valid_station() {
+        assert!(is_valid_station("boat-1"));
+        for station in ["", "a,b", "a*b", "a\\b", "a!b"] {
+            assert!(!is_valid_station(station), "{}", station);
+        }
+    }
+
+    #[test]
+    fn test_zda() {
+        assert_eq!(fix().zda("GN"), "$GNZDA,123519.25,15,05,2025,00,00*76\r\n");
+    }
+}
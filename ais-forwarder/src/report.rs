@@ -0,0 +1,177 @@
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use std::io;
+
+/// A location report as it is queued for the location endpoints, parsed back into its fields.
+/// The queued form is the vessel id directly followed by an RMC sentence, for example
+/// `244123456$GNRMC,123519.00,A,5301.12345,N,00524.12345,E,5.2,181.0,150525,,,A*hh`, and
+/// optionally more sentences on the following lines. The heading, navigational status and
+/// rate of turn are taken from a `$PAISF` sentence when there is one.
+/// A JSON record, as sent with `format=json`, is understood as well.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocationReport {
+    pub id: String,
+    pub timestamp: Option<DateTime<Utc>>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub sog: Option<f64>,
+    pub cog: Option<f64>,
+    pub heading: Option<f64>,
+    pub nav_status: Option<u8>,
+    pub rot: Option<f64>,
+    pub nmea: String,
+}
+
+impl LocationReport {
+    /// Parse a queued message for endpoint `key`, for the senders that need its fields.
+    pub fn from_message(message: &[u8], key: &str) -> io::Result<Self> {
+        let message = String::from_utf8_lossy(message);
+        Self::parse(&message).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{}: cannot parse location report '{}'",
+                    key,
+                    message.trim_end()
+                ),
+            )
+        })
+    }
+
+    pub fn parse(message: &str) -> Option<Self> {
+        if message.starts_with('{') {
+            return Self::parse_json(message);
+        }
+        let mut lines = message.lines();
+        let line = lines.next()?.trim_end();
+        let i = line.find('$')?;
+        let (id, nmea) = line.split_at(i);
+        let nmea = nmea.split('*').next()?;
+        let fields = nmea.split(',').collect::<Vec<_>>();
+        if fields.len() < 10 || !fields[0].ends_with("RMC") {
+            return None;
+        }
+
+        let time = NaiveTime::parse_from_str(fields[1], "%H%M%S%.f").ok();
+        let date = NaiveDate::parse_from_str(fields[9], "%d%m%y").ok();
+        let timestamp = match (date, time) {
+            (Some(date), Some(time)) => Some(date.and_time(time).and_utc()),
+            _ => None,
+        };
+
+        let mut report = LocationReport {
+            id: id.to_string(),
+            timestamp,
+            latitude: Self::parse_lat_long(fields[3], fields[4])?,
+            longitude: Self::parse_lat_long(fields[5], fields[6])?,
+            sog: fields[7].parse::<f64>().ok(),
+            cog: fields[8].parse::<f64>().ok(),
+            heading: None,
+            nav_status: None,
+            rot: None,
+            nmea: nmea.to_string(),
+        };
+
+        let extended = lines
+            .filter_map(|line| line.split_once("$PAISF,"))
+            .filter_map(|(_, fields)| fields.split('*').next())
+            .next();
+        if let Some(extended) = extended {
+            let fields = extended.split(',').collect::<Vec<_>>();
+            report.heading = fields.first().and_then(|f| f.parse::<f64>().ok());
+            report.nav_status = fields.get(1).and_then(|f| f.parse::<u8>().ok());
+            report.rot = fields.get(2).and_then(|f| f.parse::<f64>().ok());
+        }
+        Some(report)
+    }
+
+    fn parse_json(message: &str) -> Option<Self> {
+        let json = serde_json::from_str::<serde_json::Value>(message).ok()?;
+        Some(LocationReport {
+            id: json["id"].as_str()?.to_string(),
+            timestamp: json["time"]
+                .as_str()
+                .and_then(|ts| DateTime::parse_from_rfc3339(ts).ok())
+                .map(|ts| ts.with_timezone(&Utc)),
+            latitude: json["lat"].as_f64()?,
+            longitude: json["lon"].as_f64()?,
+            sog: json["sog"].as_f64(),
+            cog: json["cog"].as_f64(),
+            heading: json["heading"].as_f64(),
+            nav_status: json["nav_status"].as_u64().map(|s| s as u8),
+            rot: json["rot"].as_f64(),
+            nmea: String::new(),
+        })
+    }
+
+    /// Convert a NMEA `dddmm.mmmmm` value with hemisphere to signed decimal degrees.
+    fn parse_lat_long(value: &str, hemisphere: &str) -> Option<f64> {
+        let value = value.parse::<f64>().ok()?;
+        let degrees = (value / 100.0).trunc();
+        let minutes = value - degrees * 100.0;
+        let value = degrees + minutes / 60.0;
+        match hemisphere {
+            "N" | "E" => Some(value),
+            "S" | "W" => Some(-value),
+            _ => None,
+        }
+    }
+}
+
+/// Validate a configured vessel id, such as a boat name, and escape it so that it can be
+/// used in location records, MQTT topics and file names on the receiving side. Characters
+/// other than ASCII letters, digits, `-`, `_` and `.` are percent encoded, so
+/// `Sea Breeze` becomes `Sea%20Breeze`.
+pub fn vessel_id(id: &str) -> Result<String, String> {
+    const MAX_LENGTH: usize = 32;
+
+    let id = id.trim();
+    if id.is_empty() {
+        return Err("vessel_id is empty".to_string());
+    }
+    if id.chars().count() > MAX_LENGTH {
+        return Err(format!("'{}' is longer than {} characters", id, MAX_LENGTH));
+    }
+    if id.chars().any(char::is_control) {
+        return Err(format!(
+            "'{}' contains control characters",
+            id.escape_debug()
+        ));
+    }
+    if id == "." || id == ".." {
+        return Err(format!("'{}' is not a valid vessel id", id));
+    }
+    Ok(id
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect())
+}
+
+/// Undo the percent encoding of `vessel_id`, for protocols that encode the id themselves,
+/// such as a query string.
+pub fn unescape_id(id: &str) -> String {
+    let bytes = id.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let decoded = match bytes[i] {
+            b'%' => id
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match decoded {
+            Some(b) => {
+                result.push(b);
+                i += 3;
+            }
+            None => {
+                result.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&result).to_string()
+}
@@ -0,0 +1,498 @@
+use chrono::{DateTime, Timelike, Utc};
+use nmea_parser::ParsedMessage;
+use nmea_parser::ais::{VesselDynamicData, VesselStaticData};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::io;
+use tungstenite::Message;
+
+use common::NetworkEndpoint;
+use common::Protocol;
+
+use crate::aivdm;
+use crate::nmea;
+use crate::report::LocationReport;
+use crate::track::METRES_PER_SECOND_PER_KNOT;
+
+// Signal K delta output and input, see https://signalk.org/specification/latest/doc/data_model.html
+//
+// signalk-ws://host:3000?token=...   Deltas are sent as text messages on the WebSocket stream,
+//                                    by default at /signalk/v1/stream.
+// signalk-tcp://host:8375            Deltas are sent as newline separated JSON.
+//
+// As a provider, signalk-ws://host:3000 subscribes to the position of all vessels.
+//
+// Signal K uses SI units, so speeds are converted to m/s and angles to radians.
+
+pub fn send_ais_signalk(
+    message: &ParsedMessage,
+    key: &str,
+    address: &mut NetworkEndpoint,
+) -> io::Result<()> {
+    let delta = match message {
+        ParsedMessage::VesselDynamicData(data) => dynamic_delta(data),
+        ParsedMessage::VesselStaticData(data) => static_delta(data),
+        _ => return Ok(()),
+    };
+    send_delta(&delta, key, address)
+}
+
+pub fn send_message_signalk(
+    nmea_message: &[u8],
+    key: &str,
+    address: &mut NetworkEndpoint,
+) -> io::Result<()> {
+    let report = LocationReport::from_message(nmea_message, key)?;
+    send_delta(&report_delta(&report), key, address)
+}
+
+fn send_delta(delta: &Value, key: &str, address: &mut NetworkEndpoint) -> io::Result<()> {
+    match address.protocol {
+        Protocol::SignalKWs => send_websocket(&delta.to_string(), key, address),
+        _ => crate::send_tcp(format!("{}\r\n", delta).as_bytes(), key, address),
+    }
+}
+
+fn send_websocket(text: &str, key: &str, address: &mut NetworkEndpoint) -> io::Result<()> {
+    if address.websocket.is_none() {
+        let websocket = address
+            .connect_websocket()
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", key, e)))?;
+        log::info!("{}: Connected to {}", key, address);
+        address.websocket = Some(websocket);
+    }
+    if let Some(websocket) = address.websocket.as_mut() {
+        websocket.send(Message::text(text)).map_err(|e| {
+            address.websocket = None;
+            io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("send_message signalk {} ({}): {}", key, address, e),
+            )
+        })?;
+        log::debug!("{}: Sent delta to {}", key, address);
+    }
+    Ok(())
+}
+
+fn delta(mmsi: &str, timestamp: String, values: Vec<Value>) -> Value {
+    json!({
+        "context": format!("vessels.urn:mrn:imo:mmsi:{}", mmsi),
+        "updates": [{
+            "source": { "label": "ais-forwarder" },
+            "timestamp": timestamp,
+            "values": values,
+        }],
+    })
+}
+
+fn value(path: &str, value: Value) -> Value {
+    json!({ "path": path, "value": value })
+}
+
+fn now() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+fn dynamic_delta(data: &VesselDynamicData) -> Value {
+    let mut values = Vec::new();
+    if let (Some(latitude), Some(longitude)) = (data.latitude, data.longitude) {
+        values.push(value(
+            "navigation.position",
+            json!({ "latitude": latitude, "longitude": longitude }),
+        ));
+    }
+    if let Some(sog) = data.sog_knots {
+        values.push(value(
+            "navigation.speedOverGround",
+            json!(sog * METRES_PER_SECOND_PER_KNOT),
+        ));
+    }
+    if let Some(cog) = data.cog {
+        values.push(value(
+            "navigation.courseOverGroundTrue",
+            json!(cog.to_radians()),
+        ));
+    }
+    if let Some(heading) = data.heading_true {
+        values.push(value("navigation.headingTrue", json!(heading.to_radians())));
+    }
+    if let Some(rot) = data.rot {
+        // Degrees per minute to radians per second
+        values.push(value(
+            "navigation.rateOfTurn",
+            json!(rot.to_radians() / 60.0),
+        ));
+    }
+    if let Some(state) = navigation_state(data.nav_status as u8) {
+        values.push(value("navigation.state", json!(state)));
+    }
+    delta(&data.mmsi.to_string(), now(), values)
+}
+
+fn static_delta(data: &VesselStaticData) -> Value {
+    let mut values = vec![value("", json!({ "mmsi": data.mmsi.to_string() }))];
+    if let Some(name) = &data.name {
+        values.push(value("", json!({ "name": name })));
+    }
+    if let Some(call_sign) = &data.call_sign {
+        values.push(value("communication.callsignVhf", json!(call_sign)));
+    }
+    if let Some(imo) = data.imo_number {
+        values.push(value("registrations.imo", json!(format!("IMO {}", imo))));
+    }
+    values.push(value(
+        "design.aisShipType",
+        json!({ "id": data.ship_type as u8 }),
+    ));
+    if let (Some(bow), Some(stern)) = (data.dimension_to_bow, data.dimension_to_stern) {
+        values.push(value("design.length", json!({ "overall": bow + stern })));
+    }
+    if let (Some(port), Some(starboard)) = (data.dimension_to_port, data.dimension_to_starboard) {
+        values.push(value("design.beam", json!(port + starboard)));
+    }
+    if let Some(destination) = &data.destination {
+        values.push(value(
+            "navigation.destination.commonName",
+            json!(destination),
+        ));
+    }
+    delta(&data.mmsi.to_string(), now(), values)
+}
+
+// The location thread puts the MMSI in the id of reports for Signal K endpoints.
+fn report_delta(report: &LocationReport) -> Value {
+    let mut values = vec![value(
+        "navigation.position",
+        json!({ "latitude": report.latitude, "longitude": report.longitude }),
+    )];
+    if let Some(sog) = report.sog {
+        values.push(value(
+            "navigation.speedOverGround",
+            json!(sog * METRES_PER_SECOND_PER_KNOT),
+        ));
+    }
+    if let Some(cog) = report.cog {
+        values.push(value(
+            "navigation.courseOverGroundTrue",
+            json!(cog.to_radians()),
+        ));
+    }
+    let timestamp = match report.timestamp {
+        Some(ts) => ts.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        None => now(),
+    };
+    delta(&report.id, timestamp, values)
+}
+
+const NAVIGATION_STATES: [(u8, &str); 10] = [
+    (0, "motoring"),
+    (1, "anchored"),
+    (2, "not under command"),
+    (3, "restricted manouverability"),
+    (4, "constrained by draft"),
+    (5, "moored"),
+    (6, "aground"),
+    (7, "fishing"),
+    (8, "sailing"),
+    (14, "ais-sart"),
+];
+
+fn navigation_state(nav_status: u8) -> Option<&'static str> {
+    NAVIGATION_STATES
+        .iter()
+        .find(|(status, _)| *status == nav_status)
+        .map(|(_, state)| *state)
+}
+
+fn nav_status(navigation_state: &str) -> Option<u8> {
+    NAVIGATION_STATES
+        .iter()
+        .find(|(_, state)| *state == navigation_state)
+        .map(|(status, _)| *status)
+}
+
+#[derive(Default)]
+struct Vessel {
+    position: aivdm::Position,
+    statics: aivdm::Static,
+}
+
+/// Turns the deltas received from a Signal K provider into NMEA 0183 sentences, so they can
+/// be handled like those of any other provider. Our own position becomes a RMC sentence,
+/// other vessels are encoded as AIVDM messages.
+pub struct SignalKInput {
+    self_context: Option<String>,
+    vessels: HashMap<String, Vessel>,
+}
+
+impl SignalKInput {
+    pub fn new() -> Self {
+        SignalKInput {
+            self_context: None,
+            vessels: HashMap::new(),
+        }
+    }
+
+    pub fn translate(&mut self, text: &str) -> String {
+        let message = match serde_json::from_str::<Value>(text) {
+            Ok(message) => message,
+            Err(e) => {
+                log::warn!("Invalid Signal K message '{}': {}", text, e);
+                return String::new();
+            }
+        };
+
+        // The hello message tells us which vessel is ours
+        if let Some(self_context) = message.get("self").and_then(Value::as_str) {
+            log::info!("Signal K server reports self as {}", self_context);
+            self.self_context = Some(match self_context.starts_with("vessels.") {
+                true => self_context.to_string(),
+                false => format!("vessels.{}", self_context),
+            });
+            return String::new();
+        }
+
+        let context = message
+            .get("context")
+            .and_then(Value::as_str)
+            .unwrap_or("vessels.self");
+        let own_vessel = context == "vessels.self" || self.self_context.as_deref() == Some(context);
+        let mmsi = context
+            .strip_prefix("vessels.urn:mrn:imo:mmsi:")
+            .and_then(|mmsi| mmsi.parse::<u32>().ok());
+        if !own_vessel && mmsi.is_none() {
+            log::trace!("Ignoring delta for {}", context);
+            return String::new();
+        }
+        let vessel = self.vessels.entry(context.to_string()).or_default();
+
+        let mut sentences = String::new();
+        let updates = message.get("updates").and_then(Value::as_array);
+        for update in updates.into_iter().flatten() {
+            let timestamp = update
+                .get("timestamp")
+                .and_then(Value::as_str)
+                .and_then(|ts| DateTime::parse_from_rfc3339(ts).ok())
+                .map(|ts| ts.with_timezone(&Utc));
+            let (position_changed, static_changed) = Self::apply_update(vessel, update);
+
+            match (position_changed, own_vessel, mmsi) {
+                (true, true, _) => {
+                    let position = &vessel.position;
+                    if let (Some(latitude), Some(longitude)) =
+                        (position.latitude, position.longitude)
+                    {
+                        sentences.push_str(&nmea::rmc(
+                            timestamp.unwrap_or_else(Utc::now),
+                            latitude,
+                            longitude,
+                            position.sog,
+                            position.cog,
+                            'A',
+                        ));
+                    }
+                }
+                (true, false, Some(mmsi)) => {
+                    vessel.position.mmsi = mmsi;
+                    vessel.position.timestamp_seconds = timestamp.map(|ts| ts.second() as u8);
+                    sentences.push_str(&aivdm::encode_position(&vessel.position, false));
+                }
+                _ => {}
+            }
+            if let (true, false, Some(mmsi)) = (static_changed, own_vessel, mmsi) {
+                vessel.statics.mmsi = mmsi;
+                sentences.push_str(&aivdm::encode_static(&vessel.statics, false));
+            }
+        }
+        sentences
+    }
+
+    // Returns whether the position and/or static data of the vessel changed.
+    fn apply_update(vessel: &mut Vessel, update: &Value) -> (bool, bool) {
+        let mut position_changed = false;
+        let mut static_changed = false;
+        let values = update.get("values").and_then(Value::as_array);
+        for value in values.into_iter().flatten() {
+            let path = value.get("path").and_then(Value::as_str).unwrap_or("");
+            let value = match value.get("value") {
+                Some(value) => value,
+                None => continue,
+            };
+            let position = &mut vessel.position;
+            let statics = &mut vessel.statics;
+            match path {
+                "navigation.position" => {
+                    position.latitude = value.get("latitude").and_then(Value::as_f64);
+                    position.longitude = value.get("longitude").and_then(Value::as_f64);
+                    position_changed = true;
+                }
+                "navigation.speedOverGround" => {
+                    position.sog = value.as_f64().map(|sog| sog / METRES_PER_SECOND_PER_KNOT)
+                }
+                "navigation.courseOverGroundTrue" => {
+                    position.cog = value.as_f64().map(|cog| cog.to_degrees())
+                }
+                "navigation.headingTrue" => {
+                    position.heading = value.as_f64().map(|heading| heading.to_degrees())
+                }
+                "navigation.rateOfTurn" => {
+                    position.rot = value.as_f64().map(|rot| rot.to_degrees() * 60.0)
+                }
+                "navigation.state" => position.nav_status = value.as_str().and_then(nav_status),
+                "" | "name" => {
+                    let name = value.get("name").or(Some(value)).and_then(Value::as_str);
+                    if let Some(name) = name {
+                        statics.name = Some(name.to_string());
+                        static_changed = true;
+                    }
+                }
+                "communication.callsignVhf" => {
+                    statics.call_sign = value.as_str().map(|s| s.to_string());
+                    static_changed = true;
+                }
+                "registrations.imo" => {
+                    statics.imo = value
+                        .as_str()
+                        .and_then(|imo| imo.trim_start_matches("IMO").trim().parse().ok());
+                    static_changed = true;
+                }
+                "design.aisShipType" => {
+                    statics.ship_type = value.get("id").and_then(Value::as_u64).map(|id| id as u8);
+                    static_changed = true;
+                }
+                "design.length" => {
+                    // Signal K does not tell where the antenna is, so assume it is midships
+                    let length = value.get("overall").and_then(Value::as_f64);
+                    statics.to_bow = length.map(|l| (l / 2.0).round() as u16);
+                    statics.to_stern = statics.to_bow;
+                    static_changed = true;
+                }
+                "design.beam" => {
+                    let beam = value.as_f64();
+                    statics.to_port = beam.map(|b| (b / 2.0).round() as u16);
+                    statics.to_starboard = statics.to_port;
+                    static_changed = true;
+                }
+                "navigation.destination.commonName" => {
+                    statics.destination = value.as_str().map(|s| s.to_string());
+                    static_changed = true;
+                }
+                _ => {}
+            }
+        }
+        (position_changed, static_changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMESTAMP: &str = "2025-05-15T12:35:19.000Z";
+
+    fn received(context: &str, values: Value) -> String {
+        json!({
+            "context": context,
+            "updates": [{"timestamp": TIMESTAMP, "values": values}],
+        })
+        .to_string()
+    }
+
+    // The payloads of the AIVDM sentences, without the sequential message id that differs.
+    fn payloads(sentences: &str) -> Vec<String> {
+        sentences
+            .lines()
+            .map(|line| line.split(',').nth(5).unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_own_position() {
+        let mut input = SignalKInput::new();
+        let hello = json!({"name": "signalk-server", "self": "vessels.urn:mrn:imo:mmsi:244123456"});
+        assert_eq!(input.translate(&hello.to_string()), "");
+
+        let values = json!([
+            {"path": "navigation.speedOverGround", "value": 2.5},
+            {"path": "navigation.courseOverGroundTrue", "value": std::f64::consts::PI},
+            {"path": "navigation.position", "value": {"latitude": 53.018724, "longitude": -5.402057}},
+        ]);
+        let expected = nmea::rmc(
+            TIMESTAMP.parse().unwrap(),
+            53.018724,
+            -5.402057,
+            Some(2.5 / METRES_PER_SECOND_PER_KNOT),
+            Some(180.0),
+            'A',
+        );
+        let context = "vessels.urn:mrn:imo:mmsi:244123456";
+        assert_eq!(
+            input.translate(&received(context, values.clone())),
+            expected
+        );
+        assert_eq!(input.translate(&received("vessels.self", values)), expected);
+    }
+
+    #[test]
+    fn test_target_position() {
+        let mut input = SignalKInput::new();
+        let values = json!([
+            {"path": "navigation.headingTrue", "value": std::f64::consts::FRAC_PI_2},
+            {"path": "navigation.state", "value": "sailing"},
+            {"path": "navigation.position", "value": {"latitude": 53.018724, "longitude": -5.402057}},
+        ]);
+        let sentences = input.translate(&received("vessels.urn:mrn:imo:mmsi:244123456", values));
+        let expected = aivdm::Position {
+            mmsi: 244123456,
+            nav_status: nav_status("sailing"),
+            latitude: Some(53.018724),
+            longitude: Some(-5.402057),
+            heading: Some(90.0),
+            timestamp_seconds: Some(19),
+            ..Default::default()
+        };
+        assert_eq!(sentences, aivdm::encode_position(&expected, false));
+        assert!(sentences.starts_with("!AIVDM,1,1,"));
+
+        // Without a new position nothing is sent
+        let values = json!([{"path": "navigation.speedOverGround", "value": 2.5}]);
+        let context = "vessels.urn:mrn:imo:mmsi:244123456";
+        assert_eq!(input.translate(&received(context, values)), "");
+    }
+
+    #[test]
+    fn test_target_static() {
+        let mut input = SignalKInput::new();
+        let values = json!([
+            {"path": "", "value": {"name": "Sea Breeze"}},
+            {"path": "communication.callsignVhf", "value": "PD1234"},
+            {"path": "design.length", "value": {"overall": 12.0}},
+        ]);
+        let sentences = input.translate(&received("vessels.urn:mrn:imo:mmsi:244123456", values));
+        let expected = aivdm::Static {
+            mmsi: 244123456,
+            name: Some("Sea Breeze".to_string()),
+            call_sign: Some("PD1234".to_string()),
+            to_bow: Some(6),
+            to_stern: Some(6),
+            ..Default::default()
+        };
+        assert_eq!(
+            payloads(&sentences),
+            payloads(&aivdm::encode_static(&expected, false))
+        );
+    }
+
+    #[test]
+    fn test_ignored() {
+        let mut input = SignalKInput::new();
+        let values = json!([
+            {"path": "navigation.position", "value": {"latitude": 53.0, "longitude": 5.0}},
+        ]);
+        assert_eq!(
+            input.translate(&received("atons.urn:mrn:imo:mmsi:992446000", values)),
+            ""
+        );
+        assert_eq!(input.translate("not json"), "");
+    }
+}
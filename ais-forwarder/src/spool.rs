@@ -0,0 +1,339 @@
+use std::io;
+use std::time::{Duration, Instant, SystemTime};
+
+use common::NetworkEndpoint;
+use common::Protocol;
+
+use crate::cache::Persistence;
+
+const SPOOL_DEFAULT_SIZE: &str = "1000000";
+const SPOOL_DEFAULT_AGE: &str = "86400";
+const SPOOL_DEFAULT_REPLAY_RATE: &str = "10";
+// Flushing every sentence would write to disk for each AIS message during an outage, so the
+// spool is flushed after this many sentences or this much time, whichever comes first.
+const SPOOL_FLUSH_ENTRIES: usize = 100;
+const SPOOL_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+// While spooling, the endpoint is tried again after this long, doubling up to the maximum.
+const SPOOL_MIN_BACKOFF: Duration = Duration::from_secs(10);
+const SPOOL_MAX_BACKOFF: Duration = Duration::from_secs(600);
+
+// Store-and-forward of AIS sentences for endpoints that accept delayed data.
+//
+// tcp://data.aishub.net:2222?spool=true&tag_block=true
+//
+// While the endpoint cannot be reached the sentences are written to a sled tree in the cache
+// directory, and the endpoint is only tried again after a backoff so that a dead endpoint
+// does not hold up the others. Once sending works again the oldest sentences are replayed,
+// at most `replay_rate` per second, next to the live data. Only TCP endpoints can be
+// spooled, as a UDP send hardly ever fails. Options:
+// - spool_size: maximum number of bytes in the spool, default 1000000.
+// - spool_age: maximum age in seconds of spooled sentences, default 86400.
+// - replay_rate: maximum number of spooled sentences sent per second, default 10.
+//
+// When either limit is reached the oldest sentences are dropped. Combine this with
+// `tag_block=true` so that the receiver knows when the sentences were received.
+pub struct Spool {
+    tree: sled::Tree,
+    max_bytes: u64,
+    max_age: Duration,
+    replay_rate: usize,
+    bytes: u64,
+    last_replay: Instant,
+    sequence: u32,
+    unflushed: usize,
+    last_flush: Instant,
+    backoff: Option<(Duration, Instant)>, // Current backoff and when to try again
+}
+
+impl Spool {
+    pub fn new(
+        persistence: &Persistence,
+        key: &str,
+        address: &NetworkEndpoint,
+    ) -> io::Result<Self> {
+        if !matches!(address.protocol, Protocol::TCP) {
+            return Err(address.invalid_option(key, "spool", "only supported for tcp"));
+        }
+        let tree = persistence.open_tree(&format!("ais-spool-{}", key));
+        let bytes = tree
+            .iter()
+            .filter_map(|item| item.ok())
+            .map(|(_, value)| value.len() as u64)
+            .sum();
+        if bytes > 0 {
+            log::info!("{}: {} bytes in spool", key, bytes);
+        }
+        Ok(Spool {
+            tree,
+            max_bytes: address.parse_option(key, "spool_size", SPOOL_DEFAULT_SIZE)?,
+            max_age: Duration::from_secs(address.parse_option(
+                key,
+                "spool_age",
+                SPOOL_DEFAULT_AGE,
+            )?),
+            replay_rate: address.parse_option(key, "replay_rate", SPOOL_DEFAULT_REPLAY_RATE)?,
+            bytes,
+            last_replay: Instant::now(),
+            sequence: 0,
+            unflushed: 0,
+            last_flush: Instant::now(),
+            backoff: None,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// False while sending failed recently, the caller should spool without trying to send.
+    pub fn may_send(&self) -> bool {
+        self.backoff
+            .is_none_or(|(_, retry)| Instant::now() >= retry)
+    }
+
+    /// Record whether sending worked, to decide when to try again.
+    pub fn sent(&mut self, key: &str, ok: bool) {
+        self.backoff = match (ok, self.backoff) {
+            (true, None) => None,
+            (true, Some(_)) => {
+                log::info!("{}: Sending works again", key);
+                None
+            }
+            (false, backoff) => {
+                let backoff = match backoff {
+                    Some((backoff, _)) => (backoff * 2).min(SPOOL_MAX_BACKOFF),
+                    None => SPOOL_MIN_BACKOFF,
+                };
+                log::warn!("{}: Spooling, trying again in {} s", key, backoff.as_secs());
+                Some((backoff, Instant::now() + backoff))
+            }
+        };
+    }
+
+    pub fn store(&mut self, key: &str, nmea_message: &[u8]) {
+        // Keys sort by time, the sequence number makes them unique
+        self.sequence = self.sequence.wrapping_add(1);
+        let mut db_key = now_micros().to_be_bytes().to_vec();
+        db_key.extend_from_slice(&self.sequence.to_be_bytes());
+        if let Err(e) = self.tree.insert(db_key, nmea_message) {
+            log::error!("{}: Cannot spool message: {}", key, e);
+            return;
+        }
+        self.bytes += nmea_message.len() as u64;
+        self.expire(key);
+        self.unflushed += 1;
+        if self.unflushed >= SPOOL_FLUSH_ENTRIES
+            || self.last_flush.elapsed() >= SPOOL_FLUSH_INTERVAL
+        {
+            self.flush();
+        }
+    }
+
+    /// Send the oldest spooled messages with `send`, keeping to the replay rate. Messages are
+    /// only removed once `send` succeeds.
+    pub fn replay<F>(&mut self, key: &str, mut send: F) -> io::Result<()>
+    where
+        F: FnMut(&[u8]) -> io::Result<()>,
+    {
+        if self.last_replay.elapsed() < Duration::from_secs(1) {
+            return Ok(());
+        }
+        self.last_replay = Instant::now();
+        self.expire(key);
+
+        let mut sent = 0;
+        while sent < self.replay_rate {
+            let (db_key, value) = match self.tree.first() {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(e) => {
+                    log::error!("{}: Error reading from spool: {}", key, e);
+                    break;
+                }
+            };
+            send(&value)?;
+            self.remove(&db_key, value.len());
+            sent += 1;
+        }
+        if sent > 0 {
+            log::debug!(
+                "{}: Replayed {} spooled messages, {} left",
+                key,
+                sent,
+                self.tree.len()
+            );
+            self.flush();
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        let _ = self.tree.flush();
+        self.unflushed = 0;
+        self.last_flush = Instant::now();
+    }
+
+    // Drop the oldest messages until the spool is within its size and age limits.
+    fn expire(&mut self, key: &str) {
+        let oldest = now_micros().saturating_sub(self.max_age.as_micros() as u64);
+        let mut dropped = 0;
+        while let Ok(Some((db_key, value))) = self.tree.first() {
+            let received = db_key
+                .get(..8)
+                .and_then(|ts| ts.try_into().ok())
+                .map(u64::from_be_bytes)
+                .unwrap_or(0);
+            if self.bytes <= self.max_bytes && received >= oldest {
+                break;
+            }
+            self.remove(&db_key, value.len());
+            dropped += 1;
+        }
+        if dropped > 0 {
+            log::warn!("{}: Dropped {} messages from full spool", key, dropped);
+        }
+    }
+
+    fn remove(&mut self, db_key: &[u8], len: usize) {
+        if let Ok(Some(_)) = self.tree.remove(db_key) {
+            self.bytes = self.bytes.saturating_sub(len as u64);
+        }
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        if self.unflushed > 0 {
+            self.flush();
+        }
+    }
+}
+
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{CacheLimits, Eviction};
+
+    fn persistence(name: &str) -> (Persistence, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(format!(
+            "ais-forwarder-spool-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let limits = CacheLimits {
+            max_entries: 0,
+            max_bytes: 0,
+            max_age: 0,
+            eviction: Eviction::Oldest,
+            thin_minutes: 10,
+        };
+        (Persistence::new(&dir.display().to_string(), limits), dir)
+    }
+
+    fn spool(persistence: &Persistence, options: &str) -> io::Result<Spool> {
+        let address = format!("tcp://127.0.0.1:2222?spool=true&{}", options)
+            .parse::<NetworkEndpoint>()
+            .unwrap();
+        Spool::new(persistence, "test", &address)
+    }
+
+    // Replay as if the last replay was more than a second ago.
+    fn replay(spool: &mut Spool, send: impl FnMut(&[u8]) -> io::Result<()>) -> io::Result<()> {
+        spool.last_replay = Instant::now() - Duration::from_secs(2);
+        spool.replay("test", send)
+    }
+
+    #[test]
+    fn test_replay_in_order() {
+        let (persistence, dir) = persistence("order");
+        let mut spool = spool(&persistence, "replay_rate=2").unwrap();
+        for message in ["1", "2", "3"] {
+            spool.store("test", message.as_bytes());
+        }
+        let mut sent = Vec::new();
+        let mut send = |message: &[u8]| {
+            sent.push(String::from_utf8_lossy(message).to_string());
+            Ok(())
+        };
+        replay(&mut spool, &mut send).unwrap();
+        assert!(!spool.is_empty());
+        // Not again within the second
+        spool.replay("test", &mut send).unwrap();
+        replay(&mut spool, &mut send).unwrap();
+        assert!(spool.is_empty());
+        assert_eq!(sent, ["1", "2", "3"]);
+        drop((spool, persistence));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_replay_keeps_unsent() {
+        let (persistence, dir) = persistence("unsent");
+        let mut spool = spool(&persistence, "").unwrap();
+        spool.store("test", b"1");
+        spool.store("test", b"2");
+        let e = replay(&mut spool, |message| match message {
+            b"1" => Ok(()),
+            _ => Err(io::Error::other("down")),
+        })
+        .unwrap_err();
+        assert_eq!(e.to_string(), "down");
+        assert_eq!(spool.tree.len(), 1);
+        assert_eq!(spool.bytes, 1);
+        drop((spool, persistence));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let (persistence, dir) = persistence("size");
+        let mut spool = spool(&persistence, "spool_size=4").unwrap();
+        for message in ["aa", "bb", "cc"] {
+            spool.store("test", message.as_bytes());
+        }
+        let mut sent = Vec::new();
+        replay(&mut spool, |message| {
+            sent.push(message.to_vec());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(sent, [b"bb", b"cc"]);
+        drop((spool, persistence));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_backoff() {
+        let (persistence, dir) = persistence("backoff");
+        let mut spool = spool(&persistence, "").unwrap();
+        assert!(spool.may_send());
+        spool.sent("test", false);
+        assert!(!spool.may_send());
+        assert_eq!(spool.backoff.unwrap().0, SPOOL_MIN_BACKOFF);
+        spool.sent("test", false);
+        assert_eq!(spool.backoff.unwrap().0, SPOOL_MIN_BACKOFF * 2);
+        spool.sent("test", true);
+        assert!(spool.may_send());
+        drop((spool, persistence));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_tcp_only() {
+        let (persistence, dir) = persistence("udp");
+        let address = "udp://127.0.0.1:2222?spool=true"
+            .parse::<NetworkEndpoint>()
+            .unwrap();
+        assert!(Spool::new(&persistence, "test", &address).is_err());
+        assert!(spool(&persistence, "replay_rate=fast").is_err());
+        drop(persistence);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
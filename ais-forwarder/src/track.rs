@@ -0,0 +1,161 @@
+use chrono::{DateTime, Utc};
+
+pub const METRES_PER_DEGREE: f64 = 111_320.0;
+pub const METRES_PER_SECOND_PER_KNOT: f64 = 1852.0 / 3600.0;
+const EARTH_RADIUS: f64 = 6_371_000.0; // Metres
+
+#[derive(Debug, Clone, Copy)]
+pub struct TrackPoint {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub timestamp: Option<DateTime<Utc>>,
+}
+
+/// Decide which points of a track to keep so that the track can still be drawn within
+/// `tolerance` metres, using the Douglas-Peucker algorithm. As a vessel at anchor would be
+/// reduced to just the first and last point, a point is also kept when the previous kept
+/// point is more than `max_gap` seconds older. The first and last point are always kept.
+pub fn simplify(points: &[TrackPoint], tolerance: f64, max_gap: i64) -> Vec<bool> {
+    let mut keep = vec![false; points.len()];
+    if points.len() <= 2 {
+        keep.fill(true);
+        return keep;
+    }
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+
+    // Iterative, as a long backlog could overflow the stack
+    let mut segments = vec![(0, points.len() - 1)];
+    while let Some((first, last)) = segments.pop() {
+        let farthest = (first + 1..last)
+            .map(|i| {
+                (
+                    i,
+                    distance_to_segment(&points[i], &points[first], &points[last]),
+                )
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((i, distance)) = farthest
+            && distance > tolerance
+        {
+            keep[i] = true;
+            segments.push((first, i));
+            segments.push((i, last));
+        }
+    }
+
+    if max_gap > 0 {
+        let mut last_kept = points[0].timestamp;
+        for (i, point) in points.iter().enumerate() {
+            if let (Some(ts), Some(last)) = (point.timestamp, last_kept)
+                && (ts - last).num_seconds() > max_gap
+            {
+                keep[i] = true;
+            }
+            if keep[i] {
+                last_kept = point.timestamp.or(last_kept);
+            }
+        }
+    }
+    keep
+}
+
+// Distance in metres, on a flat projection around the segment, which is precise enough
+// for the distances between fixes.
+fn distance_to_segment(point: &TrackPoint, start: &TrackPoint, end: &TrackPoint) -> f64 {
+    let scale = start.latitude.to_radians().cos();
+    let project = |p: &TrackPoint| {
+        (
+            (p.longitude - start.longitude) * scale * METRES_PER_DEGREE,
+            (p.latitude - start.latitude) * METRES_PER_DEGREE,
+        )
+    };
+    let (px, py) = project(point);
+    let (ex, ey) = project(end);
+    let length2 = ex * ex + ey * ey;
+    let t = match length2 > 0.0 {
+        true => ((px * ex + py * ey) / length2).clamp(0.0, 1.0),
+        false => 0.0,
+    };
+    ((px - t * ex).powi(2) + (py - t * ey).powi(2)).sqrt()
+}
+
+/// Great circle distance in metres between two positions, using the haversine formula.
+pub fn distance(latitude1: f64, longitude1: f64, latitude2: f64, longitude2: f64) -> f64 {
+    let (phi1, phi2) = (latitude1.to_radians(), latitude2.to_radians());
+    let d_phi = phi2 - phi1;
+    let d_lambda = (longitude2 - longitude1).to_radians();
+    let a = (d_phi / 2.0).sin().powi(2) + phi1.cos() * phi2.cos() * (d_lambda / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * a.sqrt().min(1.0).asin()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A point `north` and `east` metres from 53N 5E, `minutes` after noon.
+    fn point(north: f64, east: f64, minutes: i64) -> TrackPoint {
+        let noon = "2025-05-15T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        TrackPoint {
+            latitude: 53.0 + north / METRES_PER_DEGREE,
+            longitude: 5.0 + east / (METRES_PER_DEGREE * 53.0_f64.to_radians().cos()),
+            timestamp: Some(noon + chrono::Duration::minutes(minutes)),
+        }
+    }
+
+    #[test]
+    fn test_distance() {
+        // One minute of latitude is a nautical mile
+        let d = distance(53.0, 5.0, 53.0 + 1.0 / 60.0, 5.0);
+        assert!((d - 1853.2).abs() < 1.0, "{}", d);
+        assert_eq!(distance(53.0, 5.0, 53.0, 5.0), 0.0);
+    }
+
+    #[test]
+    fn test_simplify_straight_line() {
+        let points = (0..5)
+            .map(|i| point(i as f64 * 100.0, 0.0, i))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            simplify(&points, 10.0, 0),
+            [true, false, false, false, true]
+        );
+    }
+
+    #[test]
+    fn test_simplify_keeps_corners() {
+        // North, then a turn to the east, with a 5 m wobble that is within the tolerance
+        let points = [
+            point(0.0, 0.0, 0),
+            point(100.0, 5.0, 1),
+            point(200.0, 0.0, 2),
+            point(200.0, 100.0, 3),
+            point(200.0, 200.0, 4),
+        ];
+        assert_eq!(simplify(&points, 10.0, 0), [true, false, true, false, true]);
+        // With a tolerance below the wobble, it is kept
+        assert_eq!(simplify(&points, 2.0, 0), [true, true, true, false, true]);
+    }
+
+    #[test]
+    fn test_simplify_max_gap() {
+        // At anchor for an hour, a point is kept at least every 20 minutes
+        let points = (0..=60)
+            .map(|minute| point(0.0, 0.0, minute))
+            .collect::<Vec<_>>();
+        let kept = simplify(&points, 10.0, 20 * 60)
+            .iter()
+            .enumerate()
+            .filter(|(_, keep)| **keep)
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        assert_eq!(kept, [0, 21, 42, 60]);
+    }
+
+    #[test]
+    fn test_simplify_short() {
+        let points = [point(0.0, 0.0, 0), point(100.0, 0.0, 1)];
+        assert_eq!(simplify(&points, 10.0, 0), [true, true]);
+        assert!(simplify(&[], 10.0, 0).is_empty());
+    }
+}
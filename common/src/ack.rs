@@ -0,0 +1,105 @@
+// Acknowledged delivery of location reports over TCP.
+//
+// The sender prefixes each line with a sequence number, `@<seq> <line>`. The receiver
+// answers with `ACK <first>-<last>` for each range of consecutive sequence numbers that it
+// has stored, so the sender can remove exactly those lines from its cache, and with
+// `NAK <first>-<last>` for lines that it did not store, for instance because the signature
+// was wrong or the disk is full. The sender keeps rejected lines in its cache but does not
+// wait for them any longer. Lines without a sequence number are stored as before and not
+// acknowledged, so a receiver understands both old and new senders.
+
+/// Prefix a line with its sequence number, the line should not end in CR LF.
+pub fn sequenced(sequence: u64, line: &str) -> String {
+    format!("@{} {}\r\n", sequence, line)
+}
+
+/// Split the sequence number, if any, from a received line.
+pub fn parse_sequenced(line: &str) -> (Option<u64>, &str) {
+    if let Some((sequence, rest)) = line.strip_prefix('@').and_then(|l| l.split_once(' '))
+        && let Ok(sequence) = sequence.parse::<u64>()
+    {
+        return (Some(sequence), rest);
+    }
+    (None, line)
+}
+
+/// The ACK and NAK lines for handled sequence numbers, each with whether it was stored.
+pub fn replies(handled: &[(u64, bool)]) -> String {
+    let mut replies = String::new();
+    let mut range: Option<(u64, u64, bool)> = None;
+    for &(sequence, stored) in handled {
+        range = match range {
+            Some((first, last, s)) if s == stored && sequence == last + 1 => {
+                Some((first, sequence, s))
+            }
+            Some((first, last, s)) => {
+                replies.push_str(&reply(first, last, s));
+                Some((sequence, sequence, stored))
+            }
+            None => Some((sequence, sequence, stored)),
+        };
+    }
+    if let Some((first, last, stored)) = range {
+        replies.push_str(&reply(first, last, stored));
+    }
+    replies
+}
+
+fn reply(first: u64, last: u64, stored: bool) -> String {
+    match stored {
+        true => format!("ACK {}-{}\r\n", first, last),
+        false => format!("NAK {}-{}\r\n", first, last),
+    }
+}
+
+/// Parse an ACK or NAK line into the range and whether those lines were stored.
+pub fn parse_reply(line: &str) -> Option<(u64, u64, bool)> {
+    let line = line.trim_end();
+    let (stored, range) = match (line.strip_prefix("ACK "), line.strip_prefix("NAK ")) {
+        (Some(range), _) => (true, range),
+        (_, Some(range)) => (false, range),
+        _ => return None,
+    };
+    let (first, last) = range.split_once('-')?;
+    Some((first.parse().ok()?, last.parse().ok()?, stored))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequenced() {
+        let line = sequenced(42, "244123456$GNRMC,...");
+        assert_eq!(line, "@42 244123456$GNRMC,...\r\n");
+        assert_eq!(
+            parse_sequenced(line.trim_end()),
+            (Some(42), "244123456$GNRMC,...")
+        );
+        // Lines of old senders have no sequence number
+        assert_eq!(
+            parse_sequenced("244123456$GNRMC"),
+            (None, "244123456$GNRMC")
+        );
+        assert_eq!(parse_sequenced("@x line"), (None, "@x line"));
+    }
+
+    #[test]
+    fn test_replies() {
+        let handled = [(1, true), (2, true), (3, false), (4, true), (6, true)];
+        assert_eq!(
+            replies(&handled),
+            "ACK 1-2\r\nNAK 3-3\r\nACK 4-4\r\nACK 6-6\r\n"
+        );
+        assert_eq!(replies(&[]), "");
+    }
+
+    #[test]
+    fn test_parse_reply() {
+        assert_eq!(parse_reply("ACK 1-2\r\n"), Some((1, 2, true)));
+        assert_eq!(parse_reply("NAK 3-3"), Some((3, 3, false)));
+        assert_eq!(parse_reply("ACK 1"), None);
+        assert_eq!(parse_reply("OK 1-2"), None);
+        assert_eq!(parse_reply("ACK a-b"), None);
+    }
+}
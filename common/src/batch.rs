@@ -0,0 +1,101 @@
+// Compressed batches of lines, used to upload a large backlog of location reports at once.
+//
+// A batch is a header line `#BATCH <compression> <length>` followed by `length` bytes of
+// compressed data, which holds the lines as they would otherwise be sent one by one.
+// Only gzip is supported; zstd would need a C toolchain when cross compiling for OpenWRT.
+
+use std::io::{self, Read, Write};
+
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+
+const BATCH_PREFIX: &str = "#BATCH ";
+
+/// Refuse batches larger than this, a corrupt header should not exhaust memory.
+pub const MAX_BATCH_SIZE: usize = 16 * 1024 * 1024;
+/// Refuse batches that decompress to this size or more, to guard against gzip bombs.
+pub const MAX_DECODED_SIZE: u64 = 64 * 1024 * 1024;
+
+pub fn encode(lines: &str) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(lines.as_bytes())?;
+    let payload = encoder.finish()?;
+
+    let mut batch = format!("{}gzip {}\r\n", BATCH_PREFIX, payload.len()).into_bytes();
+    batch.extend_from_slice(&payload);
+    Ok(batch)
+}
+
+/// Returns the length of the compressed data when `line` is a batch header.
+pub fn parse_header(line: &str) -> Option<io::Result<usize>> {
+    let header = line.trim_end().strip_prefix(BATCH_PREFIX)?;
+    Some(match header.split_once(' ') {
+        Some(("gzip", length)) => match length.parse::<usize>() {
+            Ok(length) if length <= MAX_BATCH_SIZE => Ok(length),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid batch length '{}'", length),
+            )),
+        },
+        _ => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("unsupported batch '{}'", header),
+        )),
+    })
+}
+
+pub fn decode(payload: &[u8]) -> io::Result<String> {
+    let mut lines = String::new();
+    GzDecoder::new(payload)
+        .take(MAX_DECODED_SIZE)
+        .read_to_string(&mut lines)?;
+    if lines.len() as u64 >= MAX_DECODED_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("batch decompresses to {} bytes or more", MAX_DECODED_SIZE),
+        ));
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let lines = "@1 244123456$GNRMC,...\r\n@2 244123456$GNRMC,...\r\n";
+        let batch = encode(lines).unwrap();
+        let end = batch.iter().position(|&b| b == b'\n').unwrap() + 1;
+        let header = std::str::from_utf8(&batch[..end]).unwrap();
+        let length = parse_header(header).unwrap().unwrap();
+        assert_eq!(length, batch.len() - end);
+        assert_eq!(decode(&batch[end..]).unwrap(), lines);
+    }
+
+    #[test]
+    fn test_parse_header() {
+        assert!(parse_header("@1 244123456$GNRMC,...").is_none());
+        assert_eq!(parse_header("#BATCH gzip 100\r\n").unwrap().unwrap(), 100);
+        let e = parse_header("#BATCH zstd 100").unwrap().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+        let too_large = format!("#BATCH gzip {}", MAX_BATCH_SIZE + 1);
+        let e = parse_header(&too_large).unwrap().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_decoded_size_limit() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        let zeros = vec![b'0'; 1024 * 1024];
+        for _ in 0..MAX_DECODED_SIZE / zeros.len() as u64 {
+            encoder.write_all(&zeros).unwrap();
+        }
+        let payload = encoder.finish().unwrap();
+        assert!(payload.len() < MAX_BATCH_SIZE);
+        let e = decode(&payload).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(decode(b"not gzip").is_err());
+    }
+}
@@ -18,6 +18,11 @@ impl<T: Read> BufReaderDirectWriter<T> {
             inner: BufReader::new(inner),
         }
     }
+
+    /// The data that has been read but not consumed yet.
+    pub fn buffer(&self) -> &[u8] {
+        self.inner.buffer()
+    }
 }
 
 impl<T: Read + ?Sized> Read for BufReaderDirectWriter<T> {
@@ -1,8 +1,16 @@
+use std::collections::HashMap;
 use std::io::{self, BufRead, Write};
 use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use tungstenite::client::IntoClientRequest;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Message, WebSocket};
+
+pub mod ack;
+pub mod batch;
 pub mod buffer;
+pub mod sign;
 use buffer::BufReaderDirectWriter;
 
 pub enum Protocol {
@@ -10,6 +18,29 @@ pub enum Protocol {
     UDP,
     TCPListen,
     UDPListen,
+    HTTP,
+    HTTPS,
+    APRS,
+    MQTT,
+    SignalKWs,
+    SignalKTcp,
+    Gpsd,
+    N2kJson,
+}
+impl Protocol {
+    /// Port to use when the address does not specify one, if the protocol has a well known port.
+    pub fn default_port(&self) -> Option<u16> {
+        match self {
+            Protocol::HTTP => Some(80),
+            Protocol::HTTPS => Some(443),
+            Protocol::MQTT => Some(1883),
+            Protocol::SignalKWs => Some(3000),
+            Protocol::SignalKTcp => Some(8375),
+            Protocol::Gpsd => Some(2947),
+            Protocol::N2kJson => Some(2597),
+            _ => None,
+        }
+    }
 }
 impl std::str::FromStr for Protocol {
     type Err = std::io::Error;
@@ -19,6 +50,14 @@ impl std::str::FromStr for Protocol {
             "udp" => Ok(Protocol::UDP),
             "tcp-listen" => Ok(Protocol::TCPListen),
             "udp-listen" => Ok(Protocol::UDPListen),
+            "http" => Ok(Protocol::HTTP),
+            "https" => Ok(Protocol::HTTPS),
+            "aprs" => Ok(Protocol::APRS),
+            "mqtt" => Ok(Protocol::MQTT),
+            "signalk-ws" => Ok(Protocol::SignalKWs),
+            "signalk-tcp" => Ok(Protocol::SignalKTcp),
+            "gpsd" => Ok(Protocol::Gpsd),
+            "n2kd-json" => Ok(Protocol::N2kJson),
             _ => Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
                 "Invalid protocol",
@@ -33,6 +72,14 @@ impl std::fmt::Display for Protocol {
             Protocol::UDP => write!(f, "udp"),
             Protocol::TCPListen => write!(f, "tcp-listen"),
             Protocol::UDPListen => write!(f, "udp-listen"),
+            Protocol::HTTP => write!(f, "http"),
+            Protocol::HTTPS => write!(f, "https"),
+            Protocol::APRS => write!(f, "aprs"),
+            Protocol::MQTT => write!(f, "mqtt"),
+            Protocol::SignalKWs => write!(f, "signalk-ws"),
+            Protocol::SignalKTcp => write!(f, "signalk-tcp"),
+            Protocol::Gpsd => write!(f, "gpsd"),
+            Protocol::N2kJson => write!(f, "n2kd-json"),
         }
     }
 }
@@ -43,16 +90,49 @@ impl std::fmt::Debug for Protocol {
             Protocol::UDP => write!(f, "udp"),
             Protocol::TCPListen => write!(f, "tcp-listen"),
             Protocol::UDPListen => write!(f, "udp-listen"),
+            Protocol::HTTP => write!(f, "http"),
+            Protocol::HTTPS => write!(f, "https"),
+            Protocol::APRS => write!(f, "aprs"),
+            Protocol::MQTT => write!(f, "mqtt"),
+            Protocol::SignalKWs => write!(f, "signalk-ws"),
+            Protocol::SignalKTcp => write!(f, "signalk-tcp"),
+            Protocol::Gpsd => write!(f, "gpsd"),
+            Protocol::N2kJson => write!(f, "n2kd-json"),
         }
     }
 }
 
+const SIGNALK_STREAM_PATH: &str = "/signalk/v1/stream";
+
+// Sent to a Signal K server after connecting, so it sends us our own position and AIS targets.
+const SIGNALK_SUBSCRIPTION: &str = r#"{"context":"vessels.*","subscribe":[
+{"path":"navigation.position","policy":"instant"},
+{"path":"navigation.speedOverGround"},
+{"path":"navigation.courseOverGroundTrue"},
+{"path":"navigation.headingTrue"},
+{"path":"navigation.rateOfTurn"},
+{"path":"navigation.state"},
+{"path":"navigation.destination.commonName"},
+{"path":"name"},
+{"path":"communication.callsignVhf"},
+{"path":"registrations.imo"},
+{"path":"design.aisShipType"},
+{"path":"design.length"},
+{"path":"design.beam"}]}"#;
+
+const GPSD_WATCH: &str = "?WATCH={\"enable\":true,\"json\":true};\n";
+
 pub struct NetworkEndpoint {
     pub protocol: Protocol,
     pub addr: SocketAddr,
+    pub host: String, // Address as configured, e.g. the hostname for HTTP
+    pub path: String, // Only used for HTTP(S) and WebSocket, always starts with '/'
+    pub options: HashMap<String, String>, // Per endpoint options, given as ?key=value&key=value
     pub tcp_listener: Option<std::net::TcpListener>,
     pub tcp_stream: Vec<BufReaderDirectWriter<std::net::TcpStream>>, // List of connected incoming TCP streams or single outgoing stream
     pub udp_socket: Option<std::net::UdpSocket>,
+    pub websocket: Option<WebSocket<MaybeTlsStream<TcpStream>>>,
+    pub last_sent: Option<Instant>, // Used by destinations that limit their rate
 }
 
 impl std::str::FromStr for NetworkEndpoint {
@@ -69,7 +149,22 @@ impl std::str::FromStr for NetworkEndpoint {
         let protocol = parts[0]
             .parse::<Protocol>()
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
-        let mut addr = parts[1].to_socket_addrs().map_err(|e| {
+        let (address, options) = match parts[1].split_once('?') {
+            Some((address, options)) => (address, parse_options(options)?),
+            None => (parts[1], HashMap::new()),
+        };
+        let (host, path) = match protocol {
+            Protocol::HTTP | Protocol::HTTPS | Protocol::SignalKWs => match address.find('/') {
+                Some(i) => (&address[..i], &address[i..]),
+                None => (address, "/"),
+            },
+            _ => (address, ""),
+        };
+        let socket_addr = match protocol.default_port() {
+            Some(port) if !host.contains(':') => format!("{}:{}", host, port),
+            _ => host.to_string(),
+        };
+        let mut addr = socket_addr.to_socket_addrs().map_err(|e| {
             std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
                 format!("{}: {}", parts[1], e),
@@ -81,20 +176,59 @@ impl std::str::FromStr for NetworkEndpoint {
         Ok(NetworkEndpoint {
             protocol,
             addr,
+            host: host.to_string(),
+            path: path.to_string(),
+            options,
             tcp_listener: None,
             tcp_stream: Vec::new(),
             udp_socket: None,
+            websocket: None,
+            last_sent: None,
         })
     }
 }
+
+fn parse_options(options: &str) -> std::io::Result<HashMap<String, String>> {
+    options
+        .split('&')
+        .filter(|option| !option.is_empty())
+        .map(|option| match option.split_once('=') {
+            Some((key, value)) => Ok((key.to_string(), value.to_string())),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid option '{}', should be key=value", option),
+            )),
+        })
+        .collect()
+}
+/// Options that hold credentials and must not be logged.
+const SECRET_OPTIONS: [&str; 4] = ["secret", "password", "passcode", "token"];
+
+/// Replace the values of credential options in an endpoint address, for logging.
+pub fn redact_options(address: &str) -> String {
+    let Some((address, options)) = address.split_once('?') else {
+        return address.to_string();
+    };
+    let options = options
+        .split('&')
+        .map(|option| match option.split_once('=') {
+            Some((key, _)) if SECRET_OPTIONS.contains(&key) => format!("{}=***", key),
+            _ => option.to_string(),
+        })
+        .collect::<Vec<_>>();
+    format!("{}?{}", address, options.join("&"))
+}
 impl std::fmt::Display for NetworkEndpoint {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}://{}", self.protocol, self.addr)
+        match self.protocol {
+            Protocol::HTTP | Protocol::HTTPS | Protocol::SignalKWs => write!(f, "{}", self.url()),
+            _ => write!(f, "{}://{}", self.protocol, self.addr),
+        }
     }
 }
 impl std::fmt::Debug for NetworkEndpoint {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}://{}", self.protocol, self.addr)
+        std::fmt::Display::fmt(self, f)
     }
 }
 impl std::convert::From<NetworkEndpoint> for SocketAddr {
@@ -133,9 +267,78 @@ pub fn read_message_tcp(stream: &mut BufReaderDirectWriter<TcpStream>) -> io::Re
 }
 
 impl NetworkEndpoint {
+    /// The URL to use for HTTP(S) and WebSocket endpoints, without the options.
+    pub fn url(&self) -> String {
+        format!("{}://{}{}", self.protocol, self.host, self.path)
+    }
+
+    /// Returns the value of option `key`, or `default` when it is not set.
+    pub fn option<'a>(&'a self, key: &str, default: &'a str) -> &'a str {
+        self.options.get(key).map(|v| v.as_str()).unwrap_or(default)
+    }
+
+    /// Parse option `option`, or `default` when it is not set. `key` names the endpoint in
+    /// the error.
+    pub fn parse_option<T: std::str::FromStr>(
+        &self,
+        key: &str,
+        option: &str,
+        default: &str,
+    ) -> io::Result<T>
+    where
+        T::Err: std::fmt::Display,
+    {
+        self.option(option, default)
+            .parse::<T>()
+            .map_err(|e| self.invalid_option(key, option, e))
+    }
+
+    /// The error for an option value that is not valid for this endpoint.
+    pub fn invalid_option(&self, key: &str, option: &str, e: impl std::fmt::Display) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} ({}): invalid {} option: {}", key, self, option, e),
+        )
+    }
+
+    /// Open a WebSocket to this endpoint, authenticated with the `token` option if given.
+    /// Signal K endpoints default to the delta stream, without any subscriptions.
+    pub fn connect_websocket(&self) -> io::Result<WebSocket<MaybeTlsStream<TcpStream>>> {
+        let path = match self.path.as_str() {
+            "/" => SIGNALK_STREAM_PATH,
+            path => path,
+        };
+        // The configured host keeps the name for virtual hosting, the port may be the default
+        let url = match self.host.contains(':') {
+            true => format!("ws://{}{}?subscribe=none", self.host, path),
+            false => format!(
+                "ws://{}:{}{}?subscribe=none",
+                self.host,
+                self.addr.port(),
+                path
+            ),
+        };
+        let mut request = url
+            .into_client_request()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", self, e)))?;
+        if let Some(token) = self.options.get("token") {
+            let value = format!("Bearer {}", token).parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{}: invalid token", self),
+                )
+            })?;
+            request.headers_mut().insert("Authorization", value);
+        }
+        let (websocket, _) = tungstenite::connect(request).map_err(|e| {
+            io::Error::new(io::ErrorKind::ConnectionRefused, format!("{}: {}", self, e))
+        })?;
+        Ok(websocket)
+    }
+
     pub fn read_to_string(&mut self) -> io::Result<String> {
         match self.protocol {
-            Protocol::TCP => {
+            Protocol::TCP | Protocol::Gpsd | Protocol::N2kJson => {
                 if self.tcp_stream.len() == 0 {
                     let stream = std::net::TcpStream::connect(self.addr).map_err(|e| {
                         std::io::Error::new(
@@ -145,7 +348,11 @@ impl NetworkEndpoint {
                     })?;
                     log::info!("Connected to {}", self);
                     stream.set_read_timeout(Some(Duration::from_secs(30)))?;
-                    let reader = BufReaderDirectWriter::new(stream);
+                    let mut reader = BufReaderDirectWriter::new(stream);
+                    if let Protocol::Gpsd = self.protocol {
+                        // Ask gpsd to start streaming reports in JSON
+                        send_message_tcp(&mut reader, GPSD_WATCH.as_bytes())?;
+                    }
                     self.tcp_stream.push(reader);
                 }
                 match read_message_tcp(&mut self.tcp_stream[0]) {
@@ -248,6 +455,62 @@ impl NetworkEndpoint {
                     return read_message_udp(udp_socket);
                 }
             }
+
+            Protocol::SignalKWs => {
+                if self.websocket.is_none() {
+                    let mut websocket = self.connect_websocket()?;
+                    if let MaybeTlsStream::Plain(stream) = websocket.get_ref() {
+                        stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+                    }
+                    websocket
+                        .send(Message::text(SIGNALK_SUBSCRIPTION))
+                        .map_err(|e| {
+                            io::Error::new(
+                                io::ErrorKind::ConnectionReset,
+                                format!("provider {}: {}", self, e),
+                            )
+                        })?;
+                    log::info!("Connected to {}", self);
+                    self.websocket = Some(websocket);
+                }
+                if let Some(websocket) = self.websocket.as_mut() {
+                    loop {
+                        match websocket.read() {
+                            Ok(Message::Text(text)) => return Ok(text.to_string()),
+                            Ok(Message::Close(_)) => {
+                                self.websocket = None;
+                                return Err(io::Error::new(
+                                    io::ErrorKind::ConnectionReset,
+                                    "WebSocket closed",
+                                ));
+                            }
+                            Ok(_) => continue, // Ping, pong and binary messages
+                            Err(e) => {
+                                log::error!("Error reading from WebSocket: {}", e);
+                                self.websocket = None;
+                                return Err(io::Error::new(
+                                    io::ErrorKind::ConnectionReset,
+                                    format!("provider {}: {}", self, e),
+                                ));
+                            }
+                        }
+                    }
+                }
+            }
+
+            Protocol::HTTP
+            | Protocol::HTTPS
+            | Protocol::APRS
+            | Protocol::MQTT
+            | Protocol::SignalKTcp => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!(
+                        "provider {}: cannot read from a {} endpoint",
+                        self, self.protocol
+                    ),
+                ));
+            }
         }
         Err(io::Error::new(
             io::ErrorKind::Other,
@@ -0,0 +1,129 @@
+// Signed location reports, so that a receiver only accepts reports from a sender that
+// knows the shared secret of the boat.
+//
+// The sender prefixes each line with the time it was sent and a HMAC-SHA256 over that time
+// and the line, `~<unix seconds> <hex hmac> <line>`. The receiver rejects lines with a wrong
+// signature, with a time that is more than `MAX_AGE` seconds off, or with a signature that
+// it has seen before. As the time is the time of sending, a cached backlog that is resent
+// after a long outage is signed again.
+//
+// Signing happens inside the sequence number of `common::ack`: `@<seq> ~<time> <hmac> <line>`.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+
+/// Maximum difference in seconds between the time in a signed line and the receiver's clock.
+pub const MAX_AGE: u64 = 300;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn mac(secret: &str, timestamp: u64, line: &str) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(format!("{} {}", timestamp, line).as_bytes());
+    mac
+}
+
+/// Sign each line of a message, which may hold more than one line.
+pub fn sign(secret: &str, timestamp: u64, message: &str) -> String {
+    let mut result = String::with_capacity(message.len() + 80);
+    for line in message.lines().filter(|line| !line.is_empty()) {
+        let signature = mac(secret, timestamp, line)
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        result.push_str(&format!("~{} {} {}\r\n", timestamp, signature, line));
+    }
+    result
+}
+
+/// Split the time and signature, if any, from a received line.
+pub fn parse_signed(line: &str) -> Option<(u64, &str, &str)> {
+    let (timestamp, rest) = line.strip_prefix('~')?.split_once(' ')?;
+    let (signature, line) = rest.split_once(' ')?;
+    Some((timestamp.parse().ok()?, signature, line))
+}
+
+/// Check the signature of a line, in constant time.
+pub fn verify(secret: &str, timestamp: u64, signature: &str, line: &str) -> bool {
+    if signature.len() != 64 || !signature.is_ascii() {
+        return false;
+    }
+    let signature = (0..signature.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&signature[i..i + 2], 16))
+        .collect::<Result<Vec<_>, _>>();
+    match signature {
+        Ok(signature) => mac(secret, timestamp, line)
+            .verify_slice(&signature)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Signatures of recently accepted lines, to reject replayed lines.
+#[derive(Debug, Default)]
+pub struct ReplayGuard {
+    seen: HashMap<String, u64>,
+}
+
+impl ReplayGuard {
+    /// Returns false when the time is too far off `now` or the signature was seen before.
+    pub fn check(&mut self, now: u64, timestamp: u64, signature: &str) -> bool {
+        if timestamp.abs_diff(now) > MAX_AGE {
+            return false;
+        }
+        self.seen
+            .retain(|_, &mut seen| seen.abs_diff(now) <= MAX_AGE);
+        self.seen.insert(signature.to_string(), timestamp).is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "secret";
+    const LINE: &str =
+        "244123456$GNRMC,123519.00,A,5301.12345,N,00524.12345,E,5.2,181.0,150525,,,A*6B";
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signed = sign(SECRET, 1747312519, LINE);
+        let (timestamp, signature, line) = parse_signed(signed.trim_end()).unwrap();
+        assert_eq!(timestamp, 1747312519);
+        assert_eq!(line, LINE);
+        assert!(verify(SECRET, timestamp, signature, line));
+        assert!(!verify("other", timestamp, signature, line));
+        assert!(!verify(SECRET, timestamp + 1, signature, line));
+        assert!(!verify(SECRET, timestamp, signature, "244123457$GNRMC"));
+        assert!(!verify(SECRET, timestamp, &signature[..62], line));
+        assert!(!verify(SECRET, timestamp, &"é".repeat(32), line));
+    }
+
+    #[test]
+    fn test_sign_lines() {
+        let signed = sign(SECRET, 1, "a\r\n\r\nb\r\n");
+        assert_eq!(signed.lines().count(), 2);
+        assert!(parse_signed(LINE).is_none());
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut guard = ReplayGuard::default();
+        let now = 1747312519;
+        assert!(guard.check(now, now - 10, "a"));
+        // The same signature again is a replay
+        assert!(!guard.check(now, now - 10, "a"));
+        assert!(guard.check(now, now + 10, "b"));
+        // Too old or too far in the future
+        assert!(!guard.check(now, now - MAX_AGE - 1, "c"));
+        assert!(!guard.check(now, now + MAX_AGE + 1, "d"));
+        // Signatures are forgotten once they are too old to be accepted anyway
+        assert!(guard.check(now + MAX_AGE + 20, now + MAX_AGE, "e"));
+        assert_eq!(guard.seen.len(), 1);
+    }
+}
@@ -1,12 +1,22 @@
 use ::time::OffsetDateTime;
 use env_logger::Env;
-use std::io::{BufRead, Write};
-use std::net::TcpListener;
+use std::collections::HashMap;
+use std::io::{BufRead, Read, Write};
+use std::net::{TcpListener, TcpStream};
 use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::SystemTime;
 
+use common::ack;
+use common::batch;
 use common::buffer::BufReaderDirectWriter;
+use common::send_message_tcp;
+use common::sign;
+
+// Shared secrets of the boats, one `<id> <secret>` per line. Messages for a boat with a
+// secret are only stored when they are signed with it, see `common::sign`.
+const KEYS_FILE: &str = "/etc/location-receiver.keys";
 
 fn main() {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
@@ -16,26 +26,67 @@ fn main() {
     let db_path = Path::new("/var/db");
     std::fs::create_dir_all(&db_path).expect("Cannot create /var/db directory");
 
+    let keys = Arc::new(load_keys(Path::new(KEYS_FILE)));
+    let replay_guard = Arc::new(Mutex::new(sign::ReplayGuard::default()));
+
     let listener = TcpListener::bind("10.67.0.1:11328").expect("Cannot bind to port 11328");
 
     loop {
         let (stream, addr) = listener.accept().expect("Failed to accept connection");
         log::info!("Accepted connection from: {}", addr);
+        let keys = keys.clone();
+        let replay_guard = replay_guard.clone();
         thread::spawn(move || {
             let mut buffer = String::new();
             let mut reader = BufReaderDirectWriter::new(stream);
+            // Sequence numbers that have been handled but not acknowledged yet, and whether
+            // the line was stored
+            let mut handled: Vec<(u64, bool)> = Vec::new();
             loop {
                 match reader.read_line(&mut buffer) {
                     Ok(0) => break, // Connection closed
                     Ok(_) => {
-                        log::info!("Received message: {}", buffer);
+                        if let Some(length) = batch::parse_header(&buffer) {
+                            match read_batch(&mut reader, length) {
+                                Ok(lines) => buffer = lines,
+                                Err(e) => {
+                                    log::error!("Error reading batch: {}", e);
+                                    break;
+                                }
+                            }
+                        }
+                        log::info!(
+                            "Received {} lines ({} bytes) from {}",
+                            buffer.lines().filter(|line| !line.is_empty()).count(),
+                            buffer.len(),
+                            addr
+                        );
+                        log::trace!("Received message: {}", buffer.trim_end());
                         // Process the message here
                         for line in buffer.lines() {
-                            if !line.is_empty() {
-                                process_message(line, &db_path);
+                            if line.is_empty() {
+                                continue;
+                            }
+                            let (sequence, line) = ack::parse_sequenced(line);
+                            let stored = match authenticate(line, &keys, &replay_guard) {
+                                Some(line) => process_message(line, &db_path),
+                                None => false,
+                            };
+                            if let Some(sequence) = sequence {
+                                handled.push((sequence, stored));
                             }
                         }
                         buffer.clear();
+
+                        // Reply once we have handled all lines the sender has sent so far
+                        if !reader.buffer().contains(&b'\n') && !handled.is_empty() {
+                            let replies = ack::replies(&handled);
+                            handled.clear();
+                            if let Err(e) = send_message_tcp(&mut reader, replies.as_bytes()) {
+                                log::error!("Error sending ack: {}", e);
+                                break;
+                            }
+                        }
                     }
                     Err(e) => {
                         log::error!("Error reading from stream: {}", e);
@@ -47,26 +98,150 @@ fn main() {
     }
 }
 
-fn process_message(message: &str, db_path: &Path) {
-    // Parse the message and handle it accordingly
-    let i = message.find('$').unwrap_or(0);
-    if i == 0 {
+fn read_batch(
+    reader: &mut BufReaderDirectWriter<TcpStream>,
+    length: std::io::Result<usize>,
+) -> std::io::Result<String> {
+    let mut payload = vec![0u8; length?];
+    reader.read_exact(&mut payload)?;
+    let lines = batch::decode(&payload)?;
+    log::debug!(
+        "Received batch of {} lines in {} bytes",
+        lines.lines().count(),
+        payload.len()
+    );
+    Ok(lines)
+}
+
+fn load_keys(path: &Path) -> HashMap<String, String> {
+    let keys = match std::fs::read_to_string(path) {
+        Ok(keys) => keys,
+        Err(e) => {
+            log::warn!(
+                "No keys from {}: {}, signatures are not checked",
+                path.display(),
+                e
+            );
+            return HashMap::new();
+        }
+    };
+    let keys = keys
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once(char::is_whitespace))
+        .map(|(id, secret)| (id.to_string(), secret.trim().to_string()))
+        .collect::<HashMap<_, _>>();
+    log::info!(
+        "Loaded keys for {} boats from {}",
+        keys.len(),
+        path.display()
+    );
+    keys
+}
+
+// Strip the signature from a message. Returns None when the boat has a secret and the
+// message is unsigned, forged or replayed, so that it is NAKed and the sender signs it again
+// the next time. A message without a valid id is passed on, it is dropped when processed.
+fn authenticate<'a>(
+    message: &'a str,
+    keys: &HashMap<String, String>,
+    replay_guard: &Mutex<sign::ReplayGuard>,
+) -> Option<&'a str> {
+    let (signature, message) = match sign::parse_signed(message) {
+        Some((timestamp, signature, message)) => (Some((timestamp, signature)), message),
+        None => (None, message),
+    };
+    let Some(id) = message_id(message) else {
+        return Some(message);
+    };
+    let Some(secret) = keys.get(&id) else {
+        return Some(message);
+    };
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    match signature {
+        None => log::warn!("{}: Rejected unsigned message '{}'", id, message),
+        Some((timestamp, signature)) if !sign::verify(secret, timestamp, signature, message) => {
+            log::warn!(
+                "{}: Rejected message with a wrong signature '{}'",
+                id,
+                message
+            )
+        }
+        Some((timestamp, signature))
+            if !replay_guard
+                .lock()
+                .unwrap()
+                .check(now, timestamp, signature) =>
+        {
+            log::warn!("{}: Rejected old or replayed message '{}'", id, message)
+        }
+        Some(_) => return Some(message),
+    }
+    None
+}
+
+// The MMSI or boat name of a message, see `process_message`.
+fn message_id(message: &str) -> Option<String> {
+    let id = match message.starts_with('{') {
+        true => serde_json::from_str::<serde_json::Value>(message)
+            .ok()?
+            .get("id")?
+            .as_str()?
+            .to_string(),
+        false => message[..message.find('$')?].to_string(),
+    };
+    is_valid_id(&id).then_some(id)
+}
+
+// Returns false when the message could not be stored and should be sent again. A message that
+// can never be stored is dropped and counts as handled, so the sender does not keep it.
+// A message is either an id (MMSI or boat name) directly followed by a NMEA sentence, stored
+// per sentence type, or a JSON record with an `id` field, stored as is.
+fn process_message(message: &str, db_path: &Path) -> bool {
+    let Some(id) = message_id(message) else {
         log::error!("No MMSI/boatname in '{}'", message);
-        return;
+        return true;
+    };
+    if message.starts_with('{') {
+        return store_message(message, &id, "json", db_path);
     }
-    let (id, message) = message.split_at(i);
-    let nmea_id = &message[3..6].to_lowercase();
+
+    let message = &message[id.len()..];
+    // Proprietary sentences, like the extended `$PAISF` record, are stored by their full name
+    let nmea_id = match message.starts_with("$P") {
+        true => message[1..]
+            .split([',', '*'])
+            .next()
+            .unwrap_or("")
+            .to_lowercase(),
+        false => message.get(3..6).unwrap_or("").to_lowercase(),
+    };
+    store_message(message, &id, &nmea_id, db_path)
+}
+
+// The id is used in a file name
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && !id.contains(['/', '\\']) && id != "." && id != ".."
+}
+
+fn store_message(message: &str, id: &str, kind: &str, db_path: &Path) -> bool {
     let message = format!("{}\r\n", message);
     let now: OffsetDateTime = SystemTime::now().into();
     let year = now.year();
 
-    let path = db_path.join(format!("{}_{}_{}.db", id, year, nmea_id));
-    let mut file = std::fs::OpenOptions::new()
+    let path = db_path.join(format!("{}_{}_{}.db", id, year, kind));
+    let result = std::fs::OpenOptions::new()
         .create(true)
         .append(true)
         .open(&path)
-        .expect("Cannot open database file");
-    if let Err(e) = file.write_all(message.as_bytes()) {
+        .and_then(|mut file| file.write_all(message.as_bytes()));
+    if let Err(e) = result {
         log::error!("Error writing to {}: {}", path.display(), e);
+        return false;
     }
+    true
 }